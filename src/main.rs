@@ -3,6 +3,8 @@
 mod utils;
 
 mod main_window;
+use std::{env, process};
+
 use log::LevelFilter;
 use main_window::MyWindow;
 
@@ -11,6 +13,20 @@ fn main() {
     let _ = simple_logging::log_to_file(logpath.to_str().unwrap(), LevelFilter::Info);
     log::info!("Logger initialized");
 
+    // Any arguments mean we're being used as a command, rather than opening the window
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        match main_window::run_command(&args) {
+            Err(e) => {
+                log::error!("Command error: {}", e);
+                eprintln!("{}", e);
+                process::exit(1);
+            },
+            Ok(output) => println!("{}", output)
+        }
+        return;
+    }
+
     let mywin = MyWindow::new();
     if let Err(e) = mywin.wnd.run_main(None) {
         log::error!("Window error: {}", e);
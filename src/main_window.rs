@@ -4,20 +4,57 @@ use mod_data::*;
 mod config;
 use config::*;
 
+mod dependency_graph;
+use dependency_graph::ModSetGraph;
+
+mod archive_limits;
+
+mod ignore_list;
+
+mod install_policy;
+
+mod backup_store;
+use backup_store::*;
+
+mod file_layers;
+
+mod apply_plan;
+use apply_plan::ApplyPlan;
+
+mod game_status;
+use game_status::*;
+
+mod snapshots;
+use snapshots::*;
+
+mod apply_history;
+use apply_history::*;
+
+mod mod_signing;
+use mod_signing::*;
+
+mod mod_library;
+use mod_library::*;
+
+mod mod_resolver;
+use mod_resolver::*;
+
+mod commands;
+pub use commands::run_command;
+
 use log::{error, info, warn};
-use crate::utils::{files::get_appdata_dir, stream::*, xdelta3::XDelta3};
+use crate::utils::{files::get_appdata_dir, paths::GamePath, stream::*, text::truncate_lines, xdelta3::XDelta3};
 
 use walkdir::WalkDir;
-use zip::{write::SimpleFileOptions, ZipWriter};
+use zip::ZipArchive;
 
 mod asref_winctrl;
 use asref_winctrl::*;
 
 mod mod_validation;
 use mod_validation::*;
-use mod_validation::ModCheckResult::*;
 
-use std::{borrow::Borrow, cell::RefCell, fs, io::{Read, Write}, ops::Index, path::PathBuf, process::Command, thread};
+use std::{borrow::Borrow, cell::RefCell, collections::HashMap, fs, io::{Read, Write}, ops::Index, path::{Path, PathBuf}, process::Command, sync::{Arc, Mutex}, thread};
 
 // Prelude automatically imports necessary traits
 use winsafe::{co::{BS, LR, SS, SW, WS, WS_EX}, gui::{self, Icon}, msg::bm::SetImage, prelude::*, BmpIcon, WString, HICON, HINSTANCE, HWND, SIZE};
@@ -96,7 +133,8 @@ pub struct MyWindow {
     pub wnd:    gui::WindowMain,
     tabs:       gui::Tab,
     menus:      Vec<WindowMenu>,
-    popup:      PopupWindow
+    popup:      PopupWindow,
+    pending_acks: Arc<Mutex<Vec<String>>>  // Warnings that get acknowledged if the user continues from the popup
 }
 
 unsafe impl Send for MyWindow {}
@@ -144,6 +182,14 @@ impl MyWindow {
                 height: 30,
                 button_style: BS::CENTER | BS::PUSHBUTTON,
                 ..Default::default()
+            },
+            gui::ButtonOpts {
+                text: "&Continue anyway".to_string(),
+                position: ((Self::POPUP_SZ.0 - 200).try_into().unwrap(), (Self::POPUP_SZ.1 - 40).try_into().unwrap()),
+                width: 120,
+                height: 30,
+                button_style: BS::CENTER | BS::PUSHBUTTON,
+                ..Default::default()
             }
         };
         let popup = PopupWindow::new(&wnd, control_opts, label_opts, button_opts);
@@ -197,6 +243,22 @@ impl MyWindow {
                 height: 40,
                 button_style: BS::CENTER | BS::PUSHBUTTON | BS::ICON,
                 ..Default::default()
+            },
+            gui::ButtonOpts {
+                text: "&Unapply".to_string(),
+                position: (794, 638),
+                width: 200,
+                height: 40,
+                button_style: BS::CENTER | BS::PUSHBUTTON,
+                ..Default::default()
+            },
+            gui::ButtonOpts {
+                text: "Un&do".to_string(),
+                position: (794, 588),
+                width: 200,
+                height: 40,
+                button_style: BS::CENTER | BS::PUSHBUTTON,
+                ..Default::default()
             }
         };
         let edit_opts = vec![];
@@ -209,7 +271,8 @@ impl MyWindow {
                     ("GUID".to_string(), 200),
                     ("Version".to_string(), 100),
                     ("Author".to_string(), 150),
-                    ("Depends on".to_string(), 400)
+                    ("Depends on".to_string(), 400),
+                    ("Signature".to_string(), 200)
                 },
                 ..Default::default()
             };
@@ -256,6 +319,22 @@ impl MyWindow {
                 height: 40,
                 button_style: BS::CENTER | BS::PUSHBUTTON,
                 ..Default::default()
+            },
+            gui::ButtonOpts {
+                text: "Refresh Backup".to_string(),
+                position: (794, 588),
+                width: 200,
+                height: 40,
+                button_style: BS::CENTER | BS::PUSHBUTTON,
+                ..Default::default()
+            },
+            gui::ButtonOpts {
+                text: "Check Files".to_string(),
+                position: (794, 538),
+                width: 200,
+                height: 40,
+                button_style: BS::CENTER | BS::PUSHBUTTON,
+                ..Default::default()
             }
         };
         let edit_opts = vec! {
@@ -284,7 +363,8 @@ impl MyWindow {
             }
         );
 
-        let new_self = Self { wnd, tabs, menus, popup };
+        let pending_acks = Arc::new(Mutex::new(vec![]));
+        let new_self = Self { wnd, tabs, menus, popup, pending_acks };
         new_self.set_btn_events();      // Events can only be set before `run_main` is executed
         new_self.set_window_ready();    // Functions such as `text()` or `items()` will fail if the window hasn't spawned yet (done in run_main), so modify them in the window ready event
         new_self
@@ -324,6 +404,10 @@ impl MyWindow {
         AppConfig::new(cfg_path)
     }
 
+    fn get_trust_store() -> TrustStore {
+        TrustStore::load(Self::get_appdata_dir().join(TrustStore::FILENAME))
+    }
+
     fn get_all_mod_paths(appdata_dir: PathBuf) -> Result<Vec<PathBuf>, String> {
         let mods_dir = appdata_dir.join(ModFile::SUBDIRECTORY);
         if !mods_dir.exists() {
@@ -353,31 +437,48 @@ impl MyWindow {
         Ok(paths)
     }
 
+    fn load_library() -> Result<ModLibrary, String> {
+        let filepaths = Self::get_all_mod_paths(Self::get_appdata_dir())?;
+
+        let mut mod_files: Vec<ModFile> = vec![];
+        for filepath in filepaths.iter() {
+            // Making a clone of the filepath so it can exist within ModData
+            match ModFile::new(filepath.to_owned()) {
+                Err(e_msg) => log::error!("{}", e_msg),
+                Ok(mf) => mod_files.push(mf)
+            }
+        }
+        Ok(ModLibrary::new(mod_files))
+    }
+
     fn fill_main_view(main_view: &gui::ListView<ModFile>, config: &AppConfig) {
         let items = main_view.items();
         if items.count() > 0 {
             items.delete_all();
         }
 
-        let filepaths = match Self::get_all_mod_paths(Self::get_appdata_dir()) {
+        let library = match Self::load_library() {
             Err(e) => {
                 log::error!("Could not get mod paths: {}", e);
                 return;
             },
-            Ok(fs) => fs
+            Ok(l) => l
         };
+        let trust_store = Self::get_trust_store();
 
-        for filepath in filepaths.iter() {
-            // Making a clone of the filepath so it can exist within ModData
-            let mod_file = match ModFile::new(filepath.to_owned()) {
-                Err(e_msg) => {
-                    log::error!("{}", e_msg);
-                    continue;
-                },
-                Ok(mf) => mf
-            };
+        for mod_file in library.iter().cloned() {
             let meta = mod_file.metadata.clone();
-            let selected = config.data_win.active_mods.contains(&meta.guid);
+            let selected = config.data_win.active_mods.iter().any(|a| {
+                if a.guid != meta.guid {
+                    false
+                }
+                else if a.version.is_empty() {
+                    library.get_highest(&a.guid) == Some(&mod_file)
+                }
+                else {
+                    a.version == meta.version
+                }
+            });
 
             let mut hard_mods: Vec<String> = vec![];
             let mut soft_mods: Vec<String> = vec![];
@@ -399,13 +500,16 @@ impl MyWindow {
             }
             depend_str.push_str(&soft_mods.join(&sep));
 
+            let signature = verify_signature_cached(&mod_file.filepath, &meta.guid, &trust_store);
+
             items.add(
                 &[
                     meta.name,
                     meta.guid,
                     meta.version,
                     meta.author,
-                    depend_str
+                    depend_str,
+                    signature.to_string()
                 ],
                 None,
                 mod_file
@@ -413,59 +517,99 @@ impl MyWindow {
         }
     }
 
+    fn get_view_library(main_view: &gui::ListView<ModFile>) -> ModLibrary {
+        let mut mod_files: Vec<ModFile> = vec![];
+        for it in main_view.items().iter() {
+            if let Some(rc_mf) = it.data() {
+                let ref_mod_file: &RefCell<ModFile> = rc_mf.borrow();
+                mod_files.push(ref_mod_file.borrow().clone());
+            }
+        }
+        ModLibrary::new(mod_files)
+    }
+
+    fn select_view_mod_files(main_view: &gui::ListView<ModFile>, mod_files: &[ModFile]) {
+        for it in main_view.items().iter() {
+            if let Some(rc_mf) = it.data() {
+                let ref_mod_file: &RefCell<ModFile> = rc_mf.borrow();
+                if mod_files.contains(&ref_mod_file.borrow()) {
+                    it.select(true);
+                }
+            }
+        }
+    }
+
     fn fill_options_menu(menu: &WindowMenu, config: &AppConfig) {
         menu.edits[0].set_text(config.data_win.game_root.to_str().unwrap());
     }
 
-    fn prepare_origin(&self, origin_path: PathBuf, game_root: PathBuf) -> Result<(), String> {
-        let foptions = SimpleFileOptions::default();
+    fn get_backup_store() -> Result<BackupStore, String> {
+        BackupStore::open(Self::get_appdata_dir().join(BackupStore::DIRNAME), Self::get_appcfg().backup_format)
+    }
 
-        let mut origin_zip = match fs::File::create(origin_path) {
-            Err(e) => return Err(e.to_string()),
-            Ok(f) => ZipWriter::new(f)
-        };
-        for entry_rslt in WalkDir::new(&game_root) {
-            // If unwrapped without a definition to hold it, it would be dropped and the compiler recognizes that. So we must do it this way.
-            if let Ok(entry) = entry_rslt {
-                let path = entry.path();
-                let rel_path = path.strip_prefix(&game_root).unwrap();
-
-                if path.is_dir() {
-                    let _ = origin_zip.add_directory_from_path(rel_path, foptions);
-                }
-                else if path.is_file() {
-                    let _ = origin_zip.start_file_from_path(rel_path, foptions);
-                    if let Ok(mut f) = fs::File::open(path) {
-                        if let Err(e) = stream_from_to::<{Self::BUFSIZE}>(|buf| f.read(buf), |buf| origin_zip.write_all(buf)) {
-                            return Err(format!("Failed to backup file {}: {}", rel_path.to_str().unwrap(), e));
-                        }
-                    }
+    fn reset_to_origin(config: &mut AppConfig) -> Result<(), String> {
+        // Earlier versions copied the whole game into origin.zip up front. Once the game is reset from it, the backup store takes over.
+        let legacy_path = Self::get_appdata_dir().join("origin.zip");
+        if legacy_path.exists() {
+            match fs::File::open(&legacy_path).map(ZipArchive::new) {
+                Err(e) => return Err(format!("Failed to open origin.zip: {}", e)),
+                // One that was cut off while it was being made has no central directory, and never held all of the game anyway
+                Ok(Err(e)) => {
+                    log::warn!("origin.zip can't be read and was likely never finished, removing it and resetting from the backups instead: {}", e);
+                    let _ = fs::remove_file(&legacy_path);
+                },
+                Ok(Ok(origin_zip)) => {
+                    Self::reset_to_legacy_origin(config, origin_zip)?;
+                    let _ = fs::remove_file(legacy_path);
+                    return Ok(());
                 }
             }
         }
+
+        let store = Self::get_backup_store()?;
+        let game_root = &config.data_win.game_root;
+        // Pending files were left by an apply that stopped before its config was saved
+        for entry in config.data_win.replaced_files.iter().chain(store.pending.iter().filter(|p| !config.data_win.replaced_files.contains(p))) {
+            if !store.restore(game_root, entry)? {
+                log::warn!("No backup of {} was found, leaving it as is", entry);
+            }
+        }
+        store.remove_created_dirs(game_root);
+
+        config.data_win.replaced_files.clear();
+        config.data_win.file_owners.clear();
         Ok(())
     }
 
-    fn reset_to_origin(config: &mut AppConfig) -> Result<(), String> {
-        let origin_path = Self::get_appdata_dir().join("origin.zip");
-        let mut origin_zip = match open_archive(&origin_path) {
-            Err(e) => return Err(format!("Failed to open origin.zip: {}", e.to_string())),
-            Ok(z) => z
-        };
+    /// Saves the config of a game that was just reset, with the mods that were applied before, and only then forgets the pending files.
+    /// Until it's saved, the next patch has to see the apply as unfinished and reset again.
+    fn save_reset(config: &AppConfig, previous_active: &[ActiveMod]) -> Result<(), String> {
+        let mut saved = config.clone();
+        saved.data_win.active_mods = previous_active.to_vec();
+        saved.save()?;
+        Self::get_backup_store()?.finish()
+    }
+
+    fn reset_to_legacy_origin(config: &mut AppConfig, mut origin_zip: ZipArchive<fs::File>) -> Result<(), String> {
+        // Mods may name a file in a different case than the game does, so look entries up by their folded path
+        let origin_names: HashMap<GamePath, String> = origin_zip.file_names()
+            .filter(|n| !GamePath::is_dir_entry(n))
+            .filter_map(|n| GamePath::parse(n).ok().map(|p| (p, n.to_string())))
+            .collect();
         for entry in config.data_win.replaced_files.iter() {
-            let out_path = config.data_win.game_root.join(entry);
-            let mut in_file = match origin_zip.by_name(entry.to_str().unwrap()) {
-                Err(_) => {
+            let out_path = config.data_win.game_root.join(entry.to_path());
+            let mut in_file = match origin_names.get(entry).map(|n| origin_zip.by_name(n)) {
+                Some(Ok(z)) => z,
+                _ => {
                     let _ = fs::remove_file(out_path);
                     continue;
-                },
-                Ok(z) => z
+                }
             };
             match fs::File::create(out_path) {
-                Err(e) => return Err(format!("Failed to extract origin file {}: {}", entry.to_str().unwrap(), e.to_string())),
+                Err(e) => return Err(format!("Failed to extract origin file {}: {}", entry, e.to_string())),
                 Ok(mut out_file) => {
                     if let Err(e) = stream_from_to::<{Self::BUFSIZE}>(|buf| in_file.read(buf), |buf| out_file.write_all(buf)) {
-                        return Err(format!("Failed to reset file {}: {}", entry.to_str().unwrap(), e));
+                        return Err(format!("Failed to reset file {}: {}", entry, e));
                     }
                 }
             }
@@ -494,12 +638,13 @@ impl MyWindow {
         }
 
         config.data_win.replaced_files.clear();
+        config.data_win.file_owners.clear();
         Ok(())
     }
 
     fn purge_to_origin(config: &mut AppConfig) -> Result<(), String> {
-        let origin_path = Self::get_appdata_dir().join("origin.zip");
-        if !origin_path.exists() {
+        let store_dir = Self::get_appdata_dir().join(BackupStore::DIRNAME);
+        if !BackupStore::exists(&store_dir) && !Self::get_appdata_dir().join("origin.zip").exists() {
             return Err("Origin not initialized".to_string());
         }
 
@@ -512,11 +657,156 @@ impl MyWindow {
             return Err(format!("Error saving config: {}", e));
         }
 
-        let _ = fs::remove_file(origin_path);
-
+        // Every file is back to how the game had it, so the next patch backs up whatever the game has then
+        if BackupStore::exists(&store_dir) {
+            Self::get_backup_store()?.delete()?;
+        }
         Ok(())
     }
 
+    /// Backs up the game's current version of files that changed since they were last backed up.
+    /// Returns those files, and the ones among them that mods had written, which the next patch applies the mods to again.
+    fn rebaseline_origin(config: &mut AppConfig) -> Result<(Vec<GamePath>, Vec<GamePath>), String> {
+        // Files replaced before their layers were recorded, or by an apply that never finished, can't be told apart from the game's own
+        let mut store = Self::get_backup_store()?;
+        let untracked = config.data_win.replaced_files.iter().any(|p| !config.data_win.file_owners.layers.contains_key(p));
+        if untracked || !store.pending.is_empty() {
+            return Err("Mods were applied by an older version or never finished applying, press \"Reset Origin\" first".to_string());
+        }
+
+        // The game wrote over what the mods left, so their layers don't describe the file anymore
+        let changed = store.changed_files(&config.data_win.game_root, &config.data_win.replaced_files, &config.data_win.file_owners);
+        let unapplied: Vec<GamePath> = changed.iter().filter(|p| config.data_win.file_owners.layers.remove(*p).is_some()).cloned().collect();
+        config.data_win.replaced_files.retain(|p| !unapplied.contains(p));
+
+        // Even with nothing changed, the game may have gained files, which the listing has to know about
+        store.rebaseline(&config.data_win.game_root, &changed, &config.data_win.file_owners)?;
+        if !unapplied.is_empty() {
+            config.save()?;
+        }
+        Ok((changed, unapplied))
+    }
+
+    /// Finds the files in the game directory that changed outside of NirvanaMM
+    fn get_game_status() -> Result<GameStatus, String> {
+        let appcfg = Self::get_appcfg();
+        let mut store = Self::get_backup_store()?;
+        if store.manifest.is_empty() {
+            if store.originals.is_empty() && store.created.is_empty() {
+                return Err("Nothing has been backed up yet, patch a mod first".to_string());
+            }
+            // Stores from before the listing was kept
+            store.record_manifest(&appcfg.data_win.game_root);
+            store.save()?;
+        }
+        Ok(check_status(&appcfg.data_win.game_root, &store, &appcfg.data_win.file_owners))
+    }
+
+    fn get_snapshot_list() -> Result<SnapshotList, String> {
+        SnapshotList::load(Self::get_appdata_dir().join(SnapshotList::FILENAME))
+    }
+
+    /// Records what is applied right now, pinning every mod to the version that was resolved for it
+    fn capture_snapshot(config: &AppConfig) -> Result<Snapshot, String> {
+        if !Self::get_backup_store()?.pending.is_empty() {
+            return Err("The last patch never finished, press \"Reset Origin\" first".to_string());
+        }
+        let library = Self::load_library()?;
+        let active_mod_files = match resolve_selection(&config.data_win.active_mods, &library) {
+            Err(errors) => return Err(format!("Could not resolve the applied mods:\n{}", errors.join("\n"))),
+            Ok(resolution) => resolution.required
+        };
+        let active_mods = active_mod_files.iter()
+            .map(|m| ActiveMod { guid: m.metadata.guid.clone(), version: m.metadata.version.clone() })
+            .collect();
+        Ok(Snapshot::new(active_mods, &config.data_win.file_owners))
+    }
+
+    /// Applies the mods of a snapshot in place of the current ones. Returns the files that didn't come out the same as when it was taken.
+    fn restore_snapshot(config: &mut AppConfig, snapshot: &Snapshot) -> Result<Vec<GamePath>, String> {
+        let library = Self::load_library()?;
+        let active_mod_files = match resolve_selection(&snapshot.active_mods, &library) {
+            Err(errors) => return Err(format!("The snapshot's mods are no longer installed:\n{}", errors.join("\n"))),
+            Ok(resolution) => resolution.required
+        };
+
+        let mut store = Self::get_backup_store()?;
+        store.repair(&config.data_win.game_root, &config.data_win.replaced_files)?;
+        if !store.changed_files(&config.data_win.game_root, &config.data_win.replaced_files, &config.data_win.file_owners).is_empty() {
+            return Err("Game files changed since they were backed up, press \"Refresh Backup\" in Options first".to_string());
+        }
+
+        // Warnings were accepted when the mods were first patched, anything new has to be looked at from the Mods menu
+        let set_acks = config.acknowledged_warnings.get(&mod_set_key(&active_mod_files)).cloned().unwrap_or_default();
+        let report = validate_active_mods(&active_mod_files, config, &Self::get_trust_store()).without_acknowledged(&set_acks);
+        if !report.is_empty() {
+            report.log();
+            return Err(format!("The snapshot's mods have problems, patch them from the Mods menu to look them over\n{}", report.summary()));
+        }
+
+        let previous_active = std::mem::replace(&mut config.data_win.active_mods, snapshot.active_mods.clone());
+        if let Err((guid, e_msg)) = Self::apply_mod_files(config, active_mod_files, &previous_active) {
+            return Err(match guid {
+                Some(g) => format!("Failed to apply mod {}\nReason: {}", g, e_msg),
+                None => format!("Failed to apply mods: {}", e_msg)
+            });
+        }
+        Self::finish_apply(config)?;
+        Ok(snapshot.differences(&config.data_win.file_owners))
+    }
+
+    fn get_apply_history() -> Result<ApplyHistory, String> {
+        ApplyHistory::load(Self::get_appdata_dir().join(ApplyHistory::FILENAME))
+    }
+
+    /// What is applied before an apply, so it can be undone. An apply that can't be undone still goes ahead.
+    fn capture_previous(config: &AppConfig) -> Option<Snapshot> {
+        match Self::capture_snapshot(config) {
+            Err(e) => {
+                log::warn!("This apply can't be undone: {}", e);
+                None
+            },
+            Ok(s) => Some(s)
+        }
+    }
+
+    fn record_apply(previous: Option<Snapshot>, config: &AppConfig) {
+        let previous = match previous {
+            None => return,
+            Some(p) => p
+        };
+        let record = ApplyRecord::new(previous, &config.data_win.file_owners);
+        // Nothing to undo when the apply failed before changing anything
+        let same_mods = record.previous.active_mods.len() == config.data_win.active_mods.len()
+            && record.previous.active_mods.iter().all(|p| config.data_win.active_mods.iter().any(|m| m.guid == p.guid));
+        if same_mods && record.changes.is_empty() {
+            return;
+        }
+        let recorded = Self::get_apply_history().and_then(|mut history| {
+            history.push(record);
+            history.save()
+        });
+        if let Err(e) = recorded {
+            log::error!("Could not record the apply: {}", e);
+        }
+    }
+
+    /// Goes back to the mods that were applied before the last apply, forgetting that apply
+    fn undo_last_apply(config: &mut AppConfig) -> Result<ApplyRecord, String> {
+        let mut history = Self::get_apply_history()?;
+        let record = match history.records.last() {
+            None => return Err("There is nothing to undo".to_string()),
+            Some(r) => r.clone()
+        };
+        let differences = Self::restore_snapshot(config, &record.previous)?;
+        if !differences.is_empty() {
+            log::warn!("Undoing gave different files than before the apply: {}", differences.iter().map(|p| p.to_string()).collect::<Vec<String>>().join(", "));
+        }
+        history.records.pop();
+        history.save()?;
+        Ok(record)
+    }
+
     fn get_current_menu(&self) -> &WindowMenu {
         let index: usize = self.tabs.items().selected().unwrap().index().try_into().unwrap();
         self.menus.get(index).unwrap()
@@ -580,9 +870,26 @@ impl MyWindow {
         });
     }
 
-    fn use_selected_data_noprep(&self, mut config: AppConfig) {
+    fn set_popup_continue_state(&self, state: bool) {
+        self.popup.buttons[1].hwnd().ShowWindow(if state {
+            SW::SHOW
+        }
+        else {
+            SW::HIDE
+        });
+    }
+
+    /// Shows a popup that lets the user apply anyway, acknowledging the given warnings
+    fn show_popup_warnings(&self, text: String, warning_ids: Vec<String>) {
+        *self.pending_acks.lock().unwrap() = warning_ids;
+        self.show_popup(text, log::Level::Debug);
+        self.set_popup_continue_state(true);
+    }
+
+    fn use_selected_data(&self, mut config: AppConfig, acknowledged: Vec<String>) {
+        let previous = Self::capture_previous(&config);
+        let previous_active = config.data_win.active_mods.clone();
         let active_mods = &mut config.data_win.active_mods;
-        let mut active_mod_files: Vec<ModFile> = vec![];
         if active_mods.len() > 0 {
             active_mods.clear();
         }
@@ -592,49 +899,87 @@ impl MyWindow {
             if let Some(rc_mf) = it.data() {
                 let ref_mod_file: &RefCell<ModFile> = rc_mf.borrow();
                 let mod_file = ref_mod_file.borrow();
-                active_mods.push(mod_file.metadata.guid.to_owned());
-                active_mod_files.push(mod_file.clone());
+                active_mods.push(ActiveMod { guid: mod_file.metadata.guid.to_owned(), version: mod_file.metadata.version.to_owned() });
             };
         }
 
-        match validate_active_mods(&active_mod_files) {
-            ModsOk() => (),
-            ModInsecurity(guid, e_msg) => {
-                self.show_popup(format!("Mod security failure: {}\nCaused by: {}", e_msg, guid), log::Level::Error);
-                return;
-            },
-            FailedDependency(deps, mods_blame) => {
-                let deps_str = deps.join(", ");
-                let blame_str = mods_blame.join(", ");
-                self.show_popup(format!("Missing dependencies: {}\nRequired by: {}", deps_str, blame_str), log::Level::Error);
+        let library = Self::get_view_library(mods_view.unwrap());
+        let (active_mod_files, optional_mods) = match resolve_selection(active_mods, &library) {
+            Err(missing) => {
+                self.show_popup(format!("Could not resolve dependencies:\n{}", missing.join("\n")), log::Level::Error);
                 return;
             },
-            FileConflict(guid, mod_conflicts, file_conflicts) => {
-                let files_str = file_conflicts.join(", ");
-                let mods_str = mod_conflicts.join(", ");
-                let patch_name = "patch.xdelta".to_string();
-                let text = if file_conflicts.contains(&patch_name) {
-                    "Incompatible patches".to_string()
+            Ok(resolution) => {
+                // Offer the required mods by selecting them, rather than applying something the user didn't pick
+                if !resolution.added.is_empty() {
+                    Self::select_view_mod_files(mods_view.unwrap(), &resolution.added);
+                    let added_str = resolution.added.iter().map(|m| format!("{} {}", m.metadata.guid, m.metadata.version)).collect::<Vec<String>>().join(", ");
+                    self.show_popup(format!("Your selection also requires: {}\nThese have been selected, press Patch again to apply them", added_str), log::Level::Info);
+                    return;
                 }
-                else {
-                    format!("Conflicting files: {}", files_str)
-                };
-                self.show_popup(format!("Mod {} is incompatible with {}\n{}", guid, mods_str, text), log::Level::Error);
+                (resolution.required, resolution.optional)
+            }
+        };
+        let optional_str =
+            if !optional_mods.is_empty() {
+                format!("\nOptional dependencies available: {}", optional_mods.iter().map(|m| m.metadata.guid.clone()).collect::<Vec<String>>().join(", "))
+            } else {
+                String::new()
+            };
+
+        // Resetting would put the old files back over the new ones, so the backup has to be refreshed first
+        let changed_files = match Self::get_backup_store() {
+            Err(e) => {
+                self.show_popup(format!("Failed to open backups: {}", e), log::Level::Error);
                 return;
             },
-            // TODO: Maybe warn and give the user the option to continue?
-            InvalidPatchNames(guid, bad_patches) => {
-                let files_str = bad_patches.join(", ");
-                self.show_popup(format!("Mod {} has patches not named patch.xdelta\n{}", guid, files_str), log::Level::Error);
-                return;
+            Ok(mut store) => {
+                match store.repair(&config.data_win.game_root, &config.data_win.replaced_files) {
+                    Err(e) => {
+                        self.show_popup(e, log::Level::Error);
+                        return;
+                    },
+                    Ok(rebuilt) if !rebuilt.is_empty() => log::warn!("Rebuilt damaged backups of {}", rebuilt.iter().map(|p| p.to_string()).collect::<Vec<String>>().join(", ")),
+                    Ok(_) => ()
+                }
+                store.changed_files(&config.data_win.game_root, &config.data_win.replaced_files, &config.data_win.file_owners)
             }
+        };
+        if !changed_files.is_empty() {
+            let files_str = changed_files.iter().map(|p| p.to_string()).collect::<Vec<String>>().join(", ");
+            self.show_popup(format!("Game files changed since they were backed up, likely from a game update: {}\nPress \"Refresh Backup\" in Options to back up the new files", files_str), log::Level::Warn);
+            return;
+        }
+
+        let set_key = mod_set_key(&active_mod_files);
+        let mut set_acks = config.acknowledged_warnings.get(&set_key).cloned().unwrap_or_default();
+        set_acks.extend(acknowledged);
+        let report = validate_active_mods(&active_mod_files, &config, &Self::get_trust_store());
+        let warning_ids = report.warning_ids();
+        let report = report.without_acknowledged(&set_acks);
+        if report.count(Severity::Error) > 0 {
+            report.log();
+            self.show_popup(format!("Cannot apply the selected mods\n{}", report.summary()), log::Level::Debug);
+            return;
+        }
+        if !report.is_empty() {
+            report.log();
+            self.show_popup_warnings(format!("The selected mods have warnings\n{}", report.summary()), report.warning_ids());
+            return;
+        }
+        // Every warning left was acknowledged by now. Only the ones that still came up are kept, so fixed ones don't pile up.
+        if warning_ids.is_empty() {
+            config.acknowledged_warnings.remove(&set_key);
+        }
+        else {
+            config.acknowledged_warnings.insert(set_key, warning_ids);
         }
 
         let self_clone = self.clone();
         self.show_popup("Applying selected mods...".to_string(), log::Level::Info);
         self.set_popup_button_state(false);
         thread::spawn(move || {
-            if let Err((guid, e_msg)) = Self::apply_mod_files(&mut config, active_mod_files) {
+            if let Err((guid, e_msg)) = Self::apply_mod_files(&mut config, active_mod_files, &previous_active) {
                 self_clone.show_popup_option(guid,
                     |g| format!("Failed to apply mod {}\nReason: {}", g, e_msg),
                     || format!("Failed to apply mods: {}", e_msg),
@@ -644,51 +989,27 @@ impl MyWindow {
                 return;
             }
 
+            let finished = Self::finish_apply(&config);
+            if finished.is_ok() {
+                Self::record_apply(previous, &config);
+            }
             self_clone.show_popup_result(
-                config.save(),
-                |_| "Patches succeeded".to_string(),
-                |e| format!("Patches succeeded\nError saving config: {}", e)
+                finished,
+                |_| format!("Patches succeeded{}", optional_str),
+                |e| format!("Patches succeeded{}\nError saving config: {}", optional_str, e)
             );
             self_clone.set_popup_button_state(true);
         });
     }
 
-    fn use_selected_data(&self, config: AppConfig) {
-        let origin_path = Self::get_appdata_dir().join("origin.zip");
-        let game_root_clone = config.data_win.game_root.clone();
-        let self_clone = self.clone();
-        if !origin_path.exists() {
-            self.show_popup("Preparing origin (this may take a while...)".to_string(), log::Level::Info);
-            self.set_popup_button_state(false);
-            // GDI can handle thread safety just fine actually, given it uses the message system with locks
-            thread::spawn(move || {
-                if let Err(e) = self_clone.prepare_origin(origin_path, game_root_clone) {
-                    self_clone.show_popup(format!("Could not prepare origin: {}", e), log::Level::Error);
-                }
-                else {
-                    self_clone.use_selected_data_noprep(config);
-                }
-                self_clone.set_popup_button_state(true);
-            });
-        }
-        else {
-            self.use_selected_data_noprep(config);
-        }
-    }
-
-    
-
-    fn apply_mod_files(config: &mut AppConfig, active_mod_files: Vec<ModFile>) -> Result<(), (Option<String>, String)> {
+    /// Applies the mods in place of what was applied before. If it fails, the game is reset and the config saved with `previous_active`.
+    fn apply_mod_files(config: &mut AppConfig, active_mod_files: Vec<ModFile>, previous_active: &[ActiveMod]) -> Result<(), (Option<String>, String)> {
         let xd3: XDelta3;
         match XDelta3::new() {
             Err(e) => return Err((None, format!("Issue with xdelta3 library: {}", e.to_string()))),
             Ok(x) => xd3 = x
         }
 
-        if let Err(e) = Self::reset_to_origin(config) {
-            return Err((None, format!("Failed to reset origin: {}", e.to_string())));
-        }
-        
         let mut chain: Vec<&ModFile> = vec![];
         for mod_file in active_mod_files.iter() {
             // Init
@@ -731,18 +1052,142 @@ impl MyWindow {
             chain.insert(dep_pos.last().unwrap() + 1, mod_file);
         }
 
+        // Files written before their layers were recorded, or by an apply that never finished, can only be undone by resetting
+        let untracked = config.data_win.replaced_files.iter().any(|p| !config.data_win.file_owners.layers.contains_key(p));
+        let interrupted = Self::get_backup_store().is_ok_and(|s| !s.pending.is_empty());
+        if untracked || interrupted || Self::get_appdata_dir().join("origin.zip").exists() {
+            if let Err(e) = Self::reset_to_origin(config).and_then(|_| Self::save_reset(config, previous_active)) {
+                return Err((None, format!("Failed to reset origin: {}", e.to_string())));
+            }
+        }
+
+        let mut store = match Self::get_backup_store() {
+            Err(e) => return Err((None, format!("Failed to open backups: {}", e))),
+            Ok(s) => s
+        };
+        // The format was changed in the config since the backups were written
+        if store.needs_migration() {
+            match store.migrate() {
+                Err(e) => return Err((None, format!("Failed to convert backups to {:?}: {}", config.backup_format, e))),
+                Ok(converted) => info!("Converted {} backups to {:?}", converted, config.backup_format)
+            }
+        }
         let bdirs = BaseDirs::new().unwrap();
         let temp_dir = bdirs.data_local_dir().join("Temp");
-        // Now that we're sorted, let's extract the contents
-        for mod_file in chain {
-            if let Err(mut e) = mod_file.extract_archive(&xd3, &config.data_win.game_root, &temp_dir, &mut config.data_win.replaced_files) {
-                if let Err(origin_err) = Self::reset_to_origin(config) {
-                    e.1.push_str(format!("\nFailed to reset origin: {}", origin_err).as_str());
+        let result = Self::apply_chain(config, &mut store, &chain, &xd3, &temp_dir);
+        if let Err((guid, mut e_msg)) = result {
+            // Leaves the config on disk matching the reset game, otherwise the next patch would trust the old ownership and skip every file
+            config.data_win.active_mods = previous_active.to_vec();
+            if let Err(origin_err) = Self::reset_to_origin(config).and_then(|_| Self::save_reset(config, previous_active)) {
+                e_msg.push_str(format!("\nFailed to reset origin: {}", origin_err).as_str());
+            }
+            return Err((guid, e_msg));
+        }
+        Ok(())
+    }
+
+    /// Brings the game from what was applied last to what the chain of mods should make of it, only writing the files that change
+    fn apply_chain(config: &mut AppConfig, store: &mut BackupStore, chain: &[&ModFile], xd3: &XDelta3, temp_dir: &Path) -> Result<(), (Option<String>, String)> {
+        for mod_file in chain.iter() {
+            let protected = mod_file.installed_paths(&config.ignore_list).and_then(|p| store.protect(&config.data_win.game_root, &p));
+            if let Err(e) = protected {
+                return Err((Some(mod_file.metadata.guid.clone()), format!("Failed to backup game files: {}", e)));
+            }
+        }
+
+        let mut plan = match ApplyPlan::stage(chain, store, &config.archive_limits, &config.ignore_list) {
+            Err((guid, e_msg)) => return Err((Some(guid), e_msg)),
+            Ok(p) => p
+        };
+        if let Err((guid, e_msg)) = plan.resolve_patches(store, xd3, temp_dir) {
+            return Err((Some(guid), e_msg));
+        }
+        // Keeps the decoded patches known about, even if writing fails
+        if let Err(e) = store.save() {
+            return Err((None, e));
+        }
+
+        match plan.write(store, &config.data_win.game_root, &mut config.data_win.file_owners) {
+            Err(e) => Err((None, format!("Failed to write game files: {}", e))),
+            Ok(replaced) => {
+                config.data_win.replaced_files = replaced;
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes one applied mod, putting back what was under its files without touching the other mods
+    fn unapply_mod(config: &mut AppConfig, guid: &str) -> Result<(), String> {
+        if !config.data_win.active_mods.iter().any(|m| m.guid == guid) {
+            return Err(format!("{} is not applied", guid));
+        }
+        let library = Self::load_library()?;
+        let active_mod_files = match resolve_selection(&config.data_win.active_mods, &library) {
+            Err(errors) => return Err(format!("Could not resolve the applied mods:\n{}", errors.join("\n"))),
+            Ok(resolution) => resolution.required
+        };
+        let mod_metas: Vec<ModMetaData> = active_mod_files.iter().map(|m| m.metadata.clone()).collect();
+        let broken = ModSetGraph::new(&mod_metas).reverse_dependencies(guid)?;
+        if !broken.is_empty() {
+            return Err(format!("{} is required by {}", guid, broken.join(", ")));
+        }
+
+        let remaining: Vec<ModFile> = active_mod_files.into_iter().filter(|m| m.metadata.guid != guid).collect();
+        let owners = &config.data_win.file_owners;
+        // A patch built on the mod's data.win has to be redone without it, as do files applied before owners were recorded
+        let needs_reapply = owners.patched_over(guid).is_some() || (owners.layers.is_empty() && !config.data_win.replaced_files.is_empty());
+        if needs_reapply {
+            let previous_active = config.data_win.active_mods.clone();
+            config.data_win.active_mods.retain(|m| m.guid != guid);
+            if let Err((_, e_msg)) = Self::apply_mod_files(config, remaining, &previous_active) {
+                config.data_win.active_mods = previous_active;
+                return Err(e_msg);
+            }
+            return Self::finish_apply(config);
+        }
+
+        // The config is only changed once every file is back, so a failure leaves it matching the game
+        let store = Self::get_backup_store()?;
+        let released = config.data_win.file_owners.remove(&store, &config.data_win.game_root, guid)?;
+        config.data_win.active_mods.retain(|m| m.guid != guid);
+        config.data_win.replaced_files.retain(|p| !released.contains(p));
+        config.save()
+    }
+
+    /// Removes each of the mods, going around again for mods that others still depended on the first time
+    fn unapply_mods(config: &mut AppConfig, mut guids: Vec<String>) -> Result<Vec<String>, String> {
+        let mut removed: Vec<String> = vec![];
+        while !guids.is_empty() {
+            let mut last_err = String::new();
+            let before = guids.len();
+            guids.retain(|g| {
+                match Self::unapply_mod(config, g) {
+                    Err(e) => {
+                        last_err = e;
+                        true
+                    },
+                    Ok(_) => {
+                        removed.push(g.clone());
+                        false
+                    }
                 }
-                return Err((Some(e.0), e.1));
+            });
+            if guids.len() == before {
+                return Err(last_err);
             }
         }
+        Ok(removed)
+    }
 
+    /// Saves which files were replaced, after which the backup store no longer has to keep track of them as pending.
+    /// Mod files nothing refers to anymore are removed from the store then, so it doesn't keep growing with every version applied.
+    fn finish_apply(config: &AppConfig) -> Result<(), String> {
+        config.save()?;
+        let mut store = Self::get_backup_store()?;
+        store.finish()?;
+        if let Err(e) = store.prune_objects(&config.data_win.file_owners) {
+            log::warn!("Could not remove unused backups: {}", e);
+        }
         Ok(())
     }
 
@@ -789,7 +1234,7 @@ impl MyWindow {
         let self_clone = self.clone();  // Re-definition because the original clone was moved away
         buttons[1].on().bn_clicked(move || {
             let appcfg = Self::get_appcfg();
-            self_clone.use_selected_data(appcfg);
+            self_clone.use_selected_data(appcfg, vec![]);
             Ok(())
         });
 
@@ -800,6 +1245,57 @@ impl MyWindow {
             Ok(())
         });
 
+        let self_clone = self.clone();
+        buttons[3].on().bn_clicked(move || {
+            let mods_view = self_clone.menus[MenuType::ModMenu].mods_view.as_ref().unwrap();
+            let appcfg = Self::get_appcfg();
+            let guids: Vec<String> = mods_view.items().iter_selected()
+                .filter_map(|it| it.data().map(|rc_mf| {
+                    let ref_mod_file: &RefCell<ModFile> = rc_mf.borrow();
+                    ref_mod_file.borrow().metadata.guid.clone()
+                }))
+                .filter(|g| appcfg.data_win.active_mods.iter().any(|m| m.guid == *g))
+                .collect();
+            if guids.is_empty() {
+                self_clone.show_popup("None of the selected mods are applied".to_string(), log::Level::Info);
+                return Ok(());
+            }
+
+            let self_clone_inner = self_clone.clone();
+            self_clone.show_popup("Removing selected mods...".to_string(), log::Level::Info);
+            self_clone.set_popup_button_state(false);
+            thread::spawn(move || {
+                let mut appcfg = appcfg;
+                let previous = Self::capture_previous(&appcfg);
+                let result = Self::unapply_mods(&mut appcfg, guids);
+                // Some mods may have been removed before one failed
+                Self::record_apply(previous, &appcfg);
+                self_clone_inner.show_popup_result(
+                    result,
+                    |removed| format!("Removed {}", removed.join(", ")),
+                    |e| format!("Failed to remove mods: {}", e)
+                );
+                self_clone_inner.set_popup_button_state(true);
+            });
+            Ok(())
+        });
+
+        let self_clone = self.clone();
+        buttons[4].on().bn_clicked(move || {
+            let self_clone_inner = self_clone.clone();
+            self_clone.show_popup("Undoing the last apply...".to_string(), log::Level::Info);
+            self_clone.set_popup_button_state(false);
+            thread::spawn(move || {
+                self_clone_inner.show_popup_result(
+                    Self::undo_last_apply(&mut Self::get_appcfg()),
+                    |record| format!("Back to {}, press Refresh to see them", record.previous),
+                    |e| format!("Failed to undo: {}", e)
+                );
+                self_clone_inner.set_popup_button_state(true);
+            });
+            Ok(())
+        });
+
         let buttons = &self.menus[MenuType::OptionsMenu].buttons;
         let self_clone = self.clone();
         buttons[0].on().bn_clicked(move || {
@@ -831,10 +1327,68 @@ impl MyWindow {
             Ok(())
         });
 
+        let self_clone = self.clone();
+        buttons[2].on().bn_clicked(move || {
+            let self_clone_inner = self_clone.clone();
+            self_clone.show_popup("Checking the game files...".to_string(), log::Level::Debug);
+            self_clone.set_popup_button_state(false);
+            thread::spawn(move || {
+                let mut appcfg = Self::get_appcfg();
+                self_clone_inner.show_popup_result(
+                    Self::rebaseline_origin(&mut appcfg),
+                    |(changed, unapplied)| if changed.is_empty() {
+                        "The backup is already up to date".to_string()
+                    }
+                    else if unapplied.is_empty() {
+                        format!("Backed up the new versions of: {}", changed.iter().map(|p| p.to_string()).collect::<Vec<String>>().join(", "))
+                    }
+                    else {
+                        format!("Backed up the new versions of: {}\nThe game replaced modded files, press Patch to apply the mods to them again: {}",
+                            changed.iter().map(|p| p.to_string()).collect::<Vec<String>>().join(", "),
+                            unapplied.iter().map(|p| p.to_string()).collect::<Vec<String>>().join(", "))
+                    },
+                    |e| format!("Failed to refresh the backup: {}", e)
+                );
+                self_clone_inner.set_popup_button_state(true);
+            });
+            Ok(())
+        });
+
+        let self_clone = self.clone();
+        buttons[3].on().bn_clicked(move || {
+            let self_clone_inner = self_clone.clone();
+            self_clone.show_popup("Checking the game files...".to_string(), log::Level::Debug);
+            self_clone.set_popup_button_state(false);
+            thread::spawn(move || {
+                match Self::get_game_status() {
+                    Err(e) => self_clone_inner.show_popup(format!("Failed to check the game files: {}", e), log::Level::Error),
+                    Ok(status) => {
+                        // The popup only fits so much, the full list goes to the log
+                        info!("{}", status);
+                        let lines: Vec<String> = status.to_string().lines().map(String::from).collect();
+                        self_clone_inner.show_popup(truncate_lines(lines, 8), log::Level::Debug);
+                    }
+                }
+                self_clone_inner.set_popup_button_state(true);
+            });
+            Ok(())
+        });
+
         let buttons = &self.popup.buttons;
         let self_clone = self.clone();
         buttons[0].on().bn_clicked(move || {
+            self_clone.pending_acks.lock().unwrap().clear();
+            self_clone.set_popup_continue_state(false);
+            self_clone.hide_popup();
+            Ok(())
+        });
+
+        let self_clone = self.clone();
+        buttons[1].on().bn_clicked(move || {
+            let acknowledged = std::mem::take(&mut *self_clone.pending_acks.lock().unwrap());
+            self_clone.set_popup_continue_state(false);
             self_clone.hide_popup();
+            self_clone.use_selected_data(Self::get_appcfg(), acknowledged);
             Ok(())
         });
     }
@@ -845,6 +1399,7 @@ impl MyWindow {
             let appcfg = Self::get_appcfg();
             let mods_view = self_clone.menus[MenuType::ModMenu].mods_view.as_ref().unwrap();
             Self::fill_main_view(mods_view, &appcfg);
+            self_clone.set_popup_continue_state(false);
             self_clone.set_btn_icons(); // Button icons must be set after our window is initialized, because SendMessage relies on their HWND's being created (done in run_main).
 
             Ok(0)
@@ -0,0 +1,94 @@
+use serde::{Deserialize, Serialize};
+
+use std::{fmt, fs, path::PathBuf};
+
+use super::file_layers::FileOwnership;
+use super::snapshots::Snapshot;
+use crate::utils::paths::GamePath;
+
+/// How one file went from one apply to the next. No hash means the game's own file.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileChange {
+    pub path: GamePath,
+    pub before: Option<String>,
+    pub after: Option<String>
+}
+
+impl fmt::Display for FileChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.before, &self.after) {
+            (None, _) => write!(f, "modded   {}", self.path),
+            (_, None) => write!(f, "restored {}", self.path),
+            _ => write!(f, "changed  {}", self.path)
+        }
+    }
+}
+
+/// An apply, with what was applied before it so it can be undone
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApplyRecord {
+    pub previous: Snapshot,
+    pub changes: Vec<FileChange>
+}
+
+impl ApplyRecord {
+    pub fn new(previous: Snapshot, owners: &FileOwnership) -> Self {
+        let current = Snapshot::new(vec![], owners);
+        let changes = previous.differences(owners).into_iter().map(|path| FileChange {
+            before: previous.files.get(&path).cloned(),
+            after: current.files.get(&path).cloned(),
+            path
+        }).collect();
+        Self { previous, changes }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct ApplyHistory {
+    #[serde(default)]
+    pub records: Vec<ApplyRecord>,  // Oldest first
+    #[serde(skip_serializing, skip_deserializing)]
+    pub filepath: PathBuf
+}
+
+impl ApplyHistory {
+    pub const FILENAME: &str = "history.toml";
+    const MAX_RECORDS: usize = 20;
+
+    pub fn load(history_path: PathBuf) -> Result<Self, String> {
+        let mut history = if !history_path.exists() {
+            Self::default()
+        }
+        else {
+            match fs::read_to_string(&history_path) {
+                Err(e) => return Err(format!("File read error: {}", e)),
+                Ok(c) => match toml::from_str::<Self>(&c) {
+                    Err(e) => return Err(format!("Apply history parse error: {}", e)),
+                    Ok(h) => h
+                }
+            }
+        };
+        history.filepath = history_path;
+        Ok(history)
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        match toml::to_string(self) {
+            Err(e) => Err(format!("Serialize error: {}", e)),
+            Ok(c) => {
+                match fs::write(&self.filepath, c) {
+                    Err(e) => Err(format!("File write error: {}", e)),
+                    Ok(_) => Ok(())
+                }
+            }
+        }
+    }
+
+    /// Adds an apply, forgetting the oldest ones past the limit
+    pub fn push(&mut self, record: ApplyRecord) {
+        self.records.push(record);
+        if self.records.len() > Self::MAX_RECORDS {
+            self.records.drain(..self.records.len() - Self::MAX_RECORDS);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::{collections::{BTreeMap, HashMap}, fs, io::Read, path::Path};
+
+use zip::ZipArchive;
+
+use super::archive_limits::ArchiveLimits;
+use super::backup_store::*;
+use super::file_layers::*;
+use super::ignore_list::IgnoreList;
+use super::mod_data::ModFile;
+use crate::utils::{paths::GamePath, stream::open_archive, workers::*, xdelta3::XDelta3};
+
+/// What a mod puts at a path. Patch outputs aren't known until the layers under them are.
+struct PlannedLayer {
+    guid: String,
+    hash: Option<String>,
+    patch: Option<String>   // SHA-256 of the patch, if the layer is built from the one below
+}
+
+/// The state the game should end up in, as every mod's layer over each path in the order they're applied
+pub struct ApplyPlan {
+    layers: BTreeMap<GamePath, Vec<PlannedLayer>>
+}
+
+impl ApplyPlan {
+    /// Stores every file of the mods in the backup store, which gives their hashes and lets them be written later without reopening the archives.
+    /// Entries stored by an earlier patch aren't extracted again, and the ones of mods that aren't in the chain anymore are forgotten.
+    /// Entries are stored in parallel, but the layers are put together in the order of the chain, so the plan comes out the same every time.
+    pub fn stage(chain: &[&ModFile], store: &mut BackupStore, limits: &ArchiveLimits, ignore_list: &IgnoreList) -> Result<Self, (String, String)> {
+        let data_win = GamePath::parse("data.win").unwrap();
+        // Each mod's position in the chain, the entry's name, where it goes, and what it's staged under
+        let mut entries: Vec<(usize, String, GamePath, String)> = vec![];
+        for (chain_pos, mod_file) in chain.iter().enumerate() {
+            let guid = &mod_file.metadata.guid;
+            let mut archive = match open_archive(&mod_file.filepath) {
+                Err(e) => return Err((guid.clone(), e)),
+                Ok(z) => z
+            };
+            let names: Vec<String> = archive.file_names().map(String::from).collect();
+            for entry in names.into_iter() {
+                if ModFile::META_ENTRIES.contains(&entry.as_str()) || ignore_list.is_ignored(&entry) || GamePath::is_dir_entry(&entry) {
+                    continue;
+                }
+                let entry_path = match GamePath::parse(&entry) {
+                    Err(e) => return Err((guid.clone(), format!("Unsafe path {}: {}", entry, e))),
+                    Ok(p) => p
+                };
+                // The CRC and size are read from the archive's directory, so nothing has to be decompressed to tell whether the entry changed
+                let stage_key = match archive.index_for_name(&entry).map(|i| archive.by_index_raw(i)) {
+                    Some(Ok(z)) => format!("{}/{}/{:08x}/{}", guid, entry, z.crc32(), z.size()),
+                    Some(Err(e)) => return Err((guid.clone(), format!("Failed to read zip content: {}", e))),
+                    None => return Err((guid.clone(), format!("Failed to read zip content: {} is missing", entry)))
+                };
+                let path = if entry == "patch.xdelta" { data_win.clone() } else { entry_path };
+                entries.push((chain_pos, entry, path, stage_key));
+            }
+        }
+
+        // Each worker keeps its own handle on every archive it reads from
+        let store_ref: &BackupStore = store;
+        let stored = parallel_map_with(&entries, HashMap::<usize, ZipArchive<fs::File>>::new, |archives, (chain_pos, entry, _, stage_key)| {
+            let staged = store_ref.staged.get(stage_key).filter(|b| b.size <= limits.max_entry_size && store_ref.object_size(&b.hash) == Some(b.size));
+            if let Some(baseline) = staged {
+                return Ok(baseline.clone());
+            }
+            if !archives.contains_key(chain_pos) {
+                archives.insert(*chain_pos, open_archive(&chain[*chain_pos].filepath)?);
+            }
+            let mut zip_file = match archives.get_mut(chain_pos).unwrap().by_name(entry) {
+                Err(e) => return Err(format!("Failed to read zip content: {}", e)),
+                Ok(z) => z
+            };
+            // Declared sizes were checked in validation, but the actual data could still be larger
+            let limit = zip_file.size().min(limits.max_entry_size);
+            match store_ref.store_reader(|buf| zip_file.read(buf), limit.try_into().unwrap_or(usize::MAX)) {
+                Err(e) => Err(format!("Failed to extract file {}: {}", entry, e)),
+                Ok(b) => Ok(b)
+            }
+        });
+
+        let mut layers: BTreeMap<GamePath, Vec<PlannedLayer>> = BTreeMap::new();
+        let mut staged: BTreeMap<String, FileBaseline> = BTreeMap::new();
+        // The limit is on how far each archive expands, not the whole chain
+        let mut totals_remaining: Vec<u64> = vec![limits.max_total_size; chain.len()];
+        for ((chain_pos, entry, path, stage_key), stored) in entries.into_iter().zip(stored) {
+            let guid = chain[chain_pos].metadata.guid.clone();
+            let stored = match stored {
+                Err(e) => return Err((guid, e)),
+                Ok(b) => b
+            };
+            let total_remaining = &mut totals_remaining[chain_pos];
+            if stored.size > *total_remaining {
+                return Err((guid, format!("Failed to extract file {}: the archive expands past the limit of {} bytes", entry, limits.max_total_size)));
+            }
+            *total_remaining -= stored.size;
+            staged.insert(stage_key, stored.clone());
+
+            let layer = if entry == "patch.xdelta" {
+                PlannedLayer { guid: guid.clone(), hash: None, patch: Some(stored.hash) }
+            }
+            else {
+                PlannedLayer { guid: guid.clone(), hash: Some(stored.hash), patch: None }
+            };
+            let stack = layers.entry(path).or_default();
+            // A mod can list the same file twice in different cases, it still only writes it once
+            stack.retain(|l| l.guid != guid);
+            stack.push(layer);
+        }
+        store.staged = staged;
+        Ok(Self { layers })
+    }
+
+    /// Works out what every patch makes of the layer under it, only decoding the ones that weren't run on the same input before
+    pub fn resolve_patches(&mut self, store: &mut BackupStore, xd3: &XDelta3, temp_dir: &Path) -> Result<(), (String, String)> {
+        for (path, stack) in self.layers.iter_mut() {
+            let mut below: Option<String> = store.originals.get(path).map(|b| b.hash.clone());
+            for layer in stack.iter_mut() {
+                if let Some(patch) = &layer.patch {
+                    let input = match &below {
+                        None => return Err((layer.guid.clone(), format!("There is no {} to patch", path))),
+                        Some(i) => i.clone()
+                    };
+                    let output = match store.find_patch_run(&input, patch) {
+                        Some(o) => o,
+                        None => {
+                            let out_path = temp_dir.join(format!("{}.patched", input));
+                            let in_paths = store.readable_path(&input, temp_dir).and_then(|i| store.readable_path(patch, temp_dir).map(|p| (i, p)));
+                            let (input_path, patch_path) = match in_paths {
+                                Err(e) => return Err((layer.guid.clone(), e)),
+                                Ok(p) => p
+                            };
+                            let decoded = xd3.decode(input_path.clone(), patch_path.clone(), out_path.clone());
+                            // Zipped backups had to be extracted for xdelta3
+                            for path in [input_path, patch_path].iter().filter(|p| p.starts_with(temp_dir)) {
+                                let _ = fs::remove_file(path);
+                            }
+                            if let Err(e_msg) = decoded {
+                                return Err((layer.guid.clone(), format!("Failed to patch due to an issue encountered by xdelta3.\n\n{}", e_msg)));
+                            }
+                            let stored = store.store_object(&out_path);
+                            let _ = fs::remove_file(&out_path);
+                            let output = match stored {
+                                Err(e) => return Err((layer.guid.clone(), e)),
+                                Ok(b) => b.hash
+                            };
+                            store.record_patch_run(PatchRun { input, patch: patch.clone(), output: output.clone() });
+                            output
+                        }
+                    };
+                    layer.hash = Some(output);
+                }
+                below = layer.hash.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes only the files whose top layer differs from what was applied before, and puts back the game's own files that no mod wants anymore.
+    /// The ownership is replaced with the planned one, and the files it covers are returned.
+    pub fn write(self, store: &BackupStore, game_root: &Path, owners: &mut FileOwnership) -> Result<Vec<GamePath>, String> {
+        let mut new_layers: BTreeMap<GamePath, Vec<FileLayer>> = BTreeMap::new();
+        let mut to_write: Vec<(GamePath, String)> = vec![];
+        for (path, stack) in self.layers.into_iter() {
+            let stack: Vec<FileLayer> = stack.into_iter()
+                .map(|l| FileLayer { guid: l.guid, hash: l.hash.unwrap(), patched: l.patch.is_some() })
+                .collect();
+            let current_top = owners.layers.get(&path).and_then(|s| s.last()).map(|l| l.hash.clone());
+            let planned_top = stack.last().unwrap().hash.clone();
+            if current_top.as_ref() != Some(&planned_top) {
+                to_write.push((path.clone(), planned_top));
+            }
+            new_layers.insert(path, stack);
+        }
+
+        // Every path is written once, so the files can be written in any order. The first failure in path order is the one reported.
+        let written = parallel_map(&to_write, |(path, hash)| {
+            let out_path = game_root.join(path.to_path());
+            if let Some(dir) = out_path.parent() {
+                let _ = fs::create_dir_all(dir);
+            }
+            store.restore_object(hash, &out_path)
+        });
+        written.into_iter().collect::<Result<Vec<()>, String>>()?;
+
+        for path in owners.layers.keys().filter(|p| !new_layers.contains_key(*p)) {
+            if !store.restore(game_root, path)? {
+                return Err(format!("No backup of {} was found", path));
+            }
+        }
+        store.remove_created_dirs(game_root);
+
+        owners.layers = new_layers;
+        Ok(owners.layers.keys().cloned().collect())
+    }
+}
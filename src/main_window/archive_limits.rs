@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+use zip::ZipArchive;
+
+use std::fs::File;
+
+/// Limits on what a mod archive may expand to, so a malicious one can't fill the disk
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ArchiveLimits {
+    pub max_total_size: u64,    // Bytes, uncompressed
+    pub max_entry_size: u64,    // Bytes, uncompressed
+    pub max_entries: usize,
+    pub max_ratio: u64          // Uncompressed size divided by compressed size
+}
+
+impl Default for ArchiveLimits {
+    fn default() -> Self {
+        ArchiveLimits {
+            max_total_size: 4 * 1024 * 1024 * 1024,
+            max_entry_size: 2 * 1024 * 1024 * 1024,
+            max_entries: 10000,
+            max_ratio: 200
+        }
+    }
+}
+
+impl ArchiveLimits {
+    // Small entries can compress very well without being a threat, such as a file full of padding
+    const RATIO_MIN_SIZE: u64 = 1024 * 1024;
+
+    /// Checks the sizes declared in the central directory. These can lie, so extraction enforces the limits again.
+    pub fn check_archive(&self, mod_zip: &mut ZipArchive<File>) -> Result<(), String> {
+        if mod_zip.len() > self.max_entries {
+            return Err(format!("Archive has {} entries, more than the limit of {}", mod_zip.len(), self.max_entries));
+        }
+
+        let mut total: u64 = 0;
+        for i in 0..mod_zip.len() {
+            let zip_file = match mod_zip.by_index_raw(i) {
+                Err(e) => return Err(format!("Failed to read zip content: {}", e)),
+                Ok(z) => z
+            };
+            let size = zip_file.size();
+            if size > self.max_entry_size {
+                return Err(format!("{} expands to {} bytes, more than the limit of {}", zip_file.name(), size, self.max_entry_size));
+            }
+            if size >= Self::RATIO_MIN_SIZE && size / zip_file.compressed_size().max(1) > self.max_ratio {
+                return Err(format!("{} has a compression ratio above the limit of {}", zip_file.name(), self.max_ratio));
+            }
+
+            total = total.saturating_add(size);
+            if total > self.max_total_size {
+                return Err(format!("Archive expands to more than the limit of {} bytes", self.max_total_size));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,588 @@
+use serde::{Deserialize, Serialize};
+
+use std::{collections::{BTreeMap, BTreeSet}, fs, io::{self, Read, Write}, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}};
+
+use walkdir::WalkDir;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use super::file_layers::FileOwnership;
+use crate::utils::{hashing::{sha256_hex, sha256_hex_copy}, paths::GamePath, workers::parallel_map};
+
+// Numbers temporary files, as several can be written into the store at once
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// How backups are kept on disk. Any of them can be read back whatever the setting, it only decides how new ones are written.
+/// Plain copies are never hardlinked into the game: a link shares its contents, so a game update or the game itself writing
+/// to the file in place would change the backup along with it, and the game could no longer be reset.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupFormat {
+    #[default]
+    Directory,  // Plain copies of the files, the fastest to back up and restore
+    Zip,        // Uncompressed zips, which check their contents against a CRC when restored
+    Zstd        // Zstandard compressed zips, the smallest but slowest
+}
+
+impl BackupFormat {
+    fn compression(&self) -> Option<CompressionMethod> {
+        match self {
+            BackupFormat::Directory => None,
+            BackupFormat::Zip => Some(CompressionMethod::Stored),
+            BackupFormat::Zstd => Some(CompressionMethod::Zstd)
+        }
+    }
+}
+
+/// What a game file was like when it was backed up
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct FileBaseline {
+    pub hash: String,   // SHA-256, also naming the backup of it
+    pub size: u64
+}
+
+/// A patch decoded before, so the same patch on the same input doesn't have to be decoded again
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct PatchRun {
+    pub input: String,  // All SHA-256
+    pub patch: String,
+    pub output: String
+}
+
+/// Keeps the vanilla version of every game file a mod has touched, stored by its SHA-256 so identical files are kept once.
+/// Files are only backed up right before a mod first writes over them, instead of copying the whole game up front.
+#[derive(Serialize, Deserialize, Default)]
+pub struct BackupStore {
+    #[serde(default)]
+    pub originals: BTreeMap<GamePath, FileBaseline>,    // Each file as the game had it
+    #[serde(default)]
+    pub created: BTreeSet<GamePath>,                    // Files and directories the game didn't have, which resetting removes
+    #[serde(default)]
+    pub pending: BTreeSet<GamePath>,                    // Written by an apply that hasn't finished, so they may be in any state
+    #[serde(default)]
+    pub patch_runs: Vec<PatchRun>,
+    #[serde(default)]
+    pub staged: BTreeMap<String, FileBaseline>,         // Mod entries stored by the last patch, by GUID, entry name, CRC and size
+    #[serde(default)]
+    pub manifest: BTreeMap<GamePath, u64>,              // Size of every file the game had when it was first backed up
+    #[serde(default)]
+    pub format: BackupFormat,                           // What every backup was last converted to, new ones may already be in another
+    #[serde(skip_serializing, skip_deserializing)]
+    write_format: BackupFormat,
+    #[serde(skip_serializing, skip_deserializing)]
+    dir: PathBuf
+}
+
+impl BackupStore {
+    pub const DIRNAME: &str = "backup";
+    const INDEX_FILENAME: &str = "index.toml";
+    const OBJECTS_DIRNAME: &str = "objects";
+
+    pub fn exists(dir: &Path) -> bool {
+        dir.join(Self::INDEX_FILENAME).exists()
+    }
+
+    pub fn open(dir: PathBuf, write_format: BackupFormat) -> Result<Self, String> {
+        let index_path = dir.join(Self::INDEX_FILENAME);
+        let mut store = if index_path.exists() {
+            match fs::read_to_string(&index_path) {
+                Err(e) => return Err(format!("File read error: {}", e)),
+                Ok(c) => match toml::from_str::<Self>(&c) {
+                    Err(e) => return Err(format!("Backup index parse error: {}", e)),
+                    Ok(s) => s
+                }
+            }
+        }
+        else {
+            Self { format: write_format, ..Default::default() }
+        };
+        store.dir = dir;
+        store.write_format = write_format;
+        Ok(store)
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if let Err(e) = fs::create_dir_all(&self.dir) {
+            return Err(format!("Could not create backup directory: {}", e));
+        }
+        let contents = match toml::to_string(self) {
+            Err(e) => return Err(format!("Serialize error: {}", e)),
+            Ok(c) => c
+        };
+        // Written aside first, so a crash can't leave half an index behind
+        let index_path = self.dir.join(Self::INDEX_FILENAME);
+        let temp_path = index_path.with_extension("toml.tmp");
+        if let Err(e) = fs::write(&temp_path, contents) {
+            return Err(format!("File write error: {}", e));
+        }
+        match fs::rename(&temp_path, &index_path) {
+            Err(e) => Err(format!("File write error: {}", e)),
+            Ok(_) => Ok(())
+        }
+    }
+
+    /// Removes the whole store, only to be done once every file is back to its original state
+    pub fn delete(self) -> Result<(), String> {
+        match fs::remove_dir_all(&self.dir) {
+            Err(e) => Err(format!("Could not remove backups: {}", e)),
+            Ok(_) => Ok(())
+        }
+    }
+
+    fn plain_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(Self::OBJECTS_DIRNAME).join(hash)
+    }
+
+    fn zip_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(Self::OBJECTS_DIRNAME).join(format!("{}.zip", hash))
+    }
+
+    /// A file no other thread is writing, which `repair` cleans up if it's left behind
+    fn temp_path(&self) -> PathBuf {
+        self.dir.join(Self::OBJECTS_DIRNAME).join(format!("incoming-{}.tmp", TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)))
+    }
+
+    /// Where a backup is, in whichever format it was written
+    fn object_file(&self, hash: &str) -> Option<PathBuf> {
+        [self.plain_path(hash), self.zip_path(hash)].into_iter().find(|p| p.is_file())
+    }
+
+    fn open_object_zip(zip_path: &Path) -> Result<ZipArchive<fs::File>, String> {
+        match fs::File::open(zip_path).map_err(|e| e.to_string()).and_then(|f| ZipArchive::new(f).map_err(|e| e.to_string())) {
+            Err(e) => Err(format!("Could not open backup {}: {}", zip_path.display(), e)),
+            Ok(z) if z.len() != 1 => Err(format!("Backup {} is damaged", zip_path.display())),
+            Ok(z) => Ok(z)
+        }
+    }
+
+    pub fn has_object(&self, hash: &str) -> bool {
+        self.object_file(hash).is_some()
+    }
+
+    /// The size of the file a backup holds, rather than of the backup itself
+    pub fn object_size(&self, hash: &str) -> Option<u64> {
+        let object_file = self.object_file(hash)?;
+        if object_file == self.plain_path(hash) {
+            return fs::metadata(object_file).ok().map(|m| m.len());
+        }
+        let mut archive = Self::open_object_zip(&object_file).ok()?;
+        let size = archive.by_index(0).ok()?.size();
+        Some(size)
+    }
+
+    /// What format a backup is in, if it's one that can be written
+    fn object_format(&self, hash: &str) -> Option<BackupFormat> {
+        let object_file = self.object_file(hash)?;
+        if object_file == self.plain_path(hash) {
+            return Some(BackupFormat::Directory);
+        }
+        let mut archive = Self::open_object_zip(&object_file).ok()?;
+        let method = archive.by_index(0).ok()?.compression();
+        [BackupFormat::Zip, BackupFormat::Zstd].into_iter().find(|f| f.compression() == Some(method))
+    }
+
+    /// Moves a file into the store as the backup of the given hash, in the format new backups are written in.
+    /// A copy in the other format is removed once this one is in place, as it would otherwise be found first.
+    fn write_object(&self, temp_path: &Path, hash: &str) -> Result<(), String> {
+        let compression = match self.write_format.compression() {
+            None => {
+                return match fs::rename(temp_path, self.plain_path(hash)) {
+                    Err(e) => Err(format!("Failed to store file: {}", e)),
+                    Ok(_) => {
+                        let _ = fs::remove_file(self.zip_path(hash));
+                        Ok(())
+                    }
+                };
+            },
+            Some(c) => c
+        };
+
+        let zip_path = self.zip_path(hash);
+        let zip_temp_path = self.temp_path();
+        let written = fs::File::open(temp_path).and_then(|mut in_file| {
+            let size = in_file.metadata()?.len();
+            let mut zip = ZipWriter::new(fs::File::create(&zip_temp_path)?);
+            let options = SimpleFileOptions::default().compression_method(compression).large_file(size >= u32::MAX as u64);
+            zip.start_file(hash, options)?;
+            io::copy(&mut in_file, &mut zip)?;
+            zip.finish()?;
+            Ok(())
+        });
+        let _ = fs::remove_file(temp_path);
+        if let Err(e) = written {
+            let _ = fs::remove_file(&zip_temp_path);
+            return Err(format!("Failed to store file: {}", e));
+        }
+        match fs::rename(&zip_temp_path, &zip_path) {
+            Err(e) => Err(format!("Failed to store file: {}", e)),
+            Ok(_) => {
+                let _ = fs::remove_file(self.plain_path(hash));
+                Ok(())
+            }
+        }
+    }
+
+    /// Stores whatever the reader gives, such as a zip entry, failing past `limit` bytes
+    pub fn store_reader(&self, read: impl FnMut(&mut [u8]) -> io::Result<usize>, limit: usize) -> Result<FileBaseline, String> {
+        let objects_dir = self.dir.join(Self::OBJECTS_DIRNAME);
+        if let Err(e) = fs::create_dir_all(&objects_dir) {
+            return Err(format!("Could not create backup directory: {}", e));
+        }
+        // The name isn't known until everything is read
+        let temp_path = self.temp_path();
+        let mut temp_file = match fs::File::create(&temp_path) {
+            Err(e) => return Err(format!("Failed to store file: {}", e)),
+            Ok(f) => f
+        };
+        let (hash, size) = sha256_hex_copy(read, |buf| temp_file.write_all(buf), limit)?;
+        drop(temp_file);
+
+        if self.has_object(&hash) {
+            let _ = fs::remove_file(&temp_path);
+        }
+        else {
+            self.write_object(&temp_path, &hash)?;
+        }
+        Ok(FileBaseline { hash, size: size as u64 })
+    }
+
+    /// The output of an earlier run of the same patch on the same input, if it's still stored
+    pub fn find_patch_run(&self, input: &str, patch: &str) -> Option<String> {
+        self.patch_runs.iter()
+            .find(|r| r.input == input && r.patch == patch && self.has_object(&r.output))
+            .map(|r| r.output.clone())
+    }
+
+    pub fn record_patch_run(&mut self, run: PatchRun) {
+        self.patch_runs.retain(|r| r.input != run.input || r.patch != run.patch);
+        self.patch_runs.push(run);
+    }
+
+    pub fn knows(&self, path: &GamePath) -> bool {
+        self.originals.contains_key(path) || self.created.contains(path)
+    }
+
+    /// Backs up whatever is at the given paths before a mod writes to them, unless it was backed up already.
+    /// Missing directories leading up to a path are recorded too. Directory entries are marked by the bool.
+    /// The files stay pending until `finish` is called, so an apply that never finished can still be undone.
+    pub fn protect(&mut self, game_root: &Path, paths: &[(GamePath, bool)]) -> Result<(), String> {
+        let mut changed = false;
+        if self.manifest.is_empty() {
+            self.record_manifest(game_root);
+            changed = true;
+        }
+        let mut to_back_up: BTreeSet<GamePath> = BTreeSet::new();
+        for (path, is_dir) in paths.iter() {
+            if !*is_dir && self.pending.insert(path.clone()) {
+                changed = true;
+            }
+
+            let mut ancestors: Vec<GamePath> = vec![];
+            let mut parent = path.to_path();
+            while parent.pop() && !parent.as_os_str().is_empty() {
+                if let Ok(p) = GamePath::parse(parent.to_str().unwrap()) {
+                    ancestors.push(p);
+                }
+            }
+            for dir in ancestors.into_iter().chain(is_dir.then(|| path.clone())) {
+                if !self.knows(&dir) && !game_root.join(dir.to_path()).exists() {
+                    self.created.insert(dir);
+                    changed = true;
+                }
+            }
+            if *is_dir || self.knows(path) || to_back_up.contains(path) {
+                continue;
+            }
+
+            if !game_root.join(path.to_path()).exists() {
+                self.created.insert(path.clone());
+            }
+            else {
+                to_back_up.insert(path.clone());
+            }
+            changed = true;
+        }
+
+        // Reading, hashing and copying the files is most of the work, and each file is its own
+        let to_back_up: Vec<GamePath> = to_back_up.into_iter().collect();
+        let stored = parallel_map(&to_back_up, |path| self.store_object(&game_root.join(path.to_path())));
+        for (path, baseline) in to_back_up.into_iter().zip(stored) {
+            self.originals.insert(path, baseline?);
+        }
+
+        // Saved before any mod writes, so the backups are known about even if applying stops partway
+        if changed {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Lists every file the game has, so files added to it later can be told apart from the game's own.
+    /// Mods may already be applied, so files they created are left out and backed up files are listed as they were backed up.
+    pub fn record_manifest(&mut self, game_root: &Path) {
+        self.manifest.clear();
+        for entry in WalkDir::new(game_root).into_iter().flatten() {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let rel_path = entry.path().strip_prefix(game_root).unwrap();
+            if let (Some(rel_str), Ok(metadata)) = (rel_path.to_str(), entry.metadata()) {
+                if let Ok(path) = GamePath::parse(rel_str) {
+                    if !self.created.contains(&path) {
+                        self.manifest.insert(path, metadata.len());
+                    }
+                }
+            }
+        }
+        for (path, baseline) in self.originals.iter() {
+            self.manifest.insert(path.clone(), baseline.size);
+        }
+    }
+
+    /// Marks the pending files as safely recorded elsewhere, once the config listing them is saved
+    pub fn finish(&mut self) -> Result<(), String> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.pending.clear();
+        self.save()
+    }
+
+    fn read_baseline(full_path: &Path) -> Result<FileBaseline, String> {
+        match fs::File::open(full_path) {
+            Err(e) => Err(format!("Could not read {}: {}", full_path.display(), e)),
+            Ok(mut f) => {
+                let size = f.metadata().map(|m| m.len()).unwrap_or(0);
+                let hash = sha256_hex(|buf| f.read(buf))?;
+                Ok(FileBaseline { hash, size })
+            }
+        }
+    }
+
+    /// Copies a file into the store without recording it as an original
+    pub fn store_object(&self, full_path: &Path) -> Result<FileBaseline, String> {
+        let baseline = Self::read_baseline(full_path)?;
+        if self.object_size(&baseline.hash) == Some(baseline.size) {
+            return Ok(baseline);
+        }
+
+        if let Err(e) = fs::create_dir_all(self.dir.join(Self::OBJECTS_DIRNAME)) {
+            return Err(format!("Could not create backup directory: {}", e));
+        }
+        let temp_path = self.temp_path();
+        if let Err(e) = fs::copy(full_path, &temp_path) {
+            return Err(format!("Failed to backup file {}: {}", full_path.display(), e));
+        }
+        match self.write_object(&temp_path, &baseline.hash) {
+            Err(e) => Err(format!("Failed to backup file {}: {}", full_path.display(), e)),
+            Ok(_) => Ok(baseline)
+        }
+    }
+
+    pub fn restore_object(&self, hash: &str, out_path: &Path) -> Result<(), String> {
+        let restored = match self.object_file(hash) {
+            None => Err(format!("no backup of {} was found", hash)),
+            // Copied rather than linked, see `BackupFormat`
+            Some(p) if p == self.plain_path(hash) => fs::copy(p, out_path).map(|_| ()).map_err(|e| e.to_string()),
+            Some(p) => {
+                // Reading to the end checks the CRC, so a damaged backup fails here instead of being written out
+                Self::open_object_zip(&p).and_then(|mut archive| {
+                    let mut zip_file = archive.by_index(0).map_err(|e| e.to_string())?;
+                    let mut out_file = fs::File::create(out_path).map_err(|e| e.to_string())?;
+                    io::copy(&mut zip_file, &mut out_file).map(|_| ()).map_err(|e| e.to_string())
+                })
+            }
+        };
+        match restored {
+            Err(e) => Err(format!("Failed to restore {}: {}", out_path.display(), e)),
+            Ok(_) => Ok(())
+        }
+    }
+
+    /// Gives a path to a backup that other programs can read, extracting it into `temp_dir` if it's zipped
+    pub fn readable_path(&self, hash: &str, temp_dir: &Path) -> Result<PathBuf, String> {
+        let plain_path = self.plain_path(hash);
+        if plain_path.is_file() {
+            return Ok(plain_path);
+        }
+        let out_path = temp_dir.join(format!("{}.backup", hash));
+        self.restore_object(hash, &out_path)?;
+        Ok(out_path)
+    }
+
+    pub fn needs_migration(&self) -> bool {
+        self.format != self.write_format
+    }
+
+    /// Rewrites every backup that isn't in the format new ones are written in. Returns how many were converted.
+    pub fn migrate(&mut self) -> Result<usize, String> {
+        let objects = match fs::read_dir(self.dir.join(Self::OBJECTS_DIRNAME)) {
+            Err(_) => return Ok(0),
+            Ok(d) => d
+        };
+        let hashes: BTreeSet<String> = objects.flatten()
+            .map(|o| o.path())
+            .filter(|p| p.extension().is_none() || p.extension().is_some_and(|e| e == "zip"))
+            .filter_map(|p| p.file_stem().map(|s| s.to_string_lossy().to_string()))
+            .collect();
+
+        let mut converted: usize = 0;
+        for hash in hashes.iter() {
+            if self.object_format(hash) == Some(self.write_format) {
+                continue;
+            }
+            // Written out in full first, so the old backup is only removed once the new one is in place
+            let temp_path = self.temp_path();
+            self.restore_object(hash, &temp_path)?;
+            self.write_object(&temp_path, hash)?;
+            converted += 1;
+        }
+
+        self.format = self.write_format;
+        self.save()?;
+        Ok(converted)
+    }
+
+    /// Puts a file back the way the game had it. Ok(false) means the store knows nothing of it.
+    pub fn restore(&self, game_root: &Path, path: &GamePath) -> Result<bool, String> {
+        let out_path = game_root.join(path.to_path());
+        if let Some(baseline) = self.originals.get(path) {
+            return match self.restore_object(&baseline.hash, &out_path) {
+                Err(e) => Err(format!("Failed to reset file {}: {}", path, e)),
+                Ok(_) => Ok(true)
+            };
+        }
+        if self.created.contains(path) {
+            if out_path.is_file() {
+                let _ = fs::remove_file(out_path);
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Removes directories that only exist because of mods, once they're empty
+    pub fn remove_created_dirs(&self, game_root: &Path) {
+        // Going backwards visits every directory before the one it's in
+        for path in self.created.iter().rev() {
+            let full_path = game_root.join(path.to_path());
+            if full_path.is_dir() && fs::read_dir(&full_path).is_ok_and(|mut d| d.next().is_none()) {
+                let _ = fs::remove_dir(full_path);
+            }
+        }
+    }
+
+    /// Finds the backed up files that changed outside of NirvanaMM, such as from a game update.
+    /// Files mods wrote are compared against what the top mod left. Ones replaced before that was recorded can't be told apart, so they're skipped.
+    pub fn changed_files(&self, game_root: &Path, replaced_files: &[GamePath], owners: &FileOwnership) -> Vec<GamePath> {
+        let mut changed: Vec<GamePath> = vec![];
+        for path in self.originals.keys().chain(self.created.iter()) {
+            if self.pending.contains(path) {
+                continue;
+            }
+            let full_path = game_root.join(path.to_path());
+            let expected = match (owners.layers.get(path).and_then(|s| s.last()), self.originals.get(path)) {
+                (Some(top), _) => Some(FileBaseline { hash: top.hash.clone(), size: self.object_size(&top.hash).unwrap_or(0) }),
+                (None, _) if replaced_files.contains(path) => continue,
+                (None, Some(baseline)) => Some(baseline.clone()),
+                (None, None) => None
+            };
+            let unchanged = match expected {
+                None => !full_path.is_file(),
+                // Comparing sizes first spares hashing most files that did change
+                Some(expected) => match fs::metadata(&full_path) {
+                    Err(_) => false,
+                    Ok(m) if m.len() != expected.size => false,
+                    Ok(_) => Self::read_baseline(&full_path).is_ok_and(|b| b == expected)
+                }
+            };
+            if !unchanged {
+                changed.push(path.clone());
+            }
+        }
+        changed
+    }
+
+    /// Takes the game's current version of the given files as their originals, dropping backups nothing refers to anymore
+    pub fn rebaseline(&mut self, game_root: &Path, paths: &[GamePath], owners: &FileOwnership) -> Result<(), String> {
+        for path in paths.iter() {
+            let full_path = game_root.join(path.to_path());
+            if full_path.is_file() {
+                let baseline = self.store_object(&full_path)?;
+                self.originals.insert(path.clone(), baseline);
+                self.created.remove(path);
+            }
+            else {
+                // Removed by the game, so whatever a mod puts there later should be removed on reset
+                self.originals.remove(path);
+                self.created.insert(path.clone());
+            }
+        }
+        self.record_manifest(game_root);
+        self.save()?;
+        self.prune_objects(owners)
+    }
+
+    /// Removes backups that no game file, mod layer or staged entry refers to, along with the patch runs that made them
+    pub fn prune_objects(&mut self, owners: &FileOwnership) -> Result<(), String> {
+        let objects = match fs::read_dir(self.dir.join(Self::OBJECTS_DIRNAME)) {
+            Err(_) => return Ok(()),
+            Ok(d) => d
+        };
+        let mut referenced: BTreeSet<String> = self.originals.values().chain(self.staged.values()).map(|b| b.hash.clone()).collect();
+        referenced.extend(owners.layers.values().flatten().map(|l| l.hash.clone()));
+        for object in objects.flatten() {
+            let object_path = object.path();
+            // Temporary files may still be being written
+            if object_path.extension().is_some_and(|e| e != "zip") {
+                continue;
+            }
+            let name = object_path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+            if !referenced.contains(&name) {
+                let _ = fs::remove_file(object_path);
+            }
+        }
+        let run_count = self.patch_runs.len();
+        self.patch_runs.retain(|r| referenced.contains(&r.output));
+        if self.patch_runs.len() != run_count {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Checks that every backup is still there and whole, taking a fresh copy from the game where its file is still untouched.
+    /// Returns the files whose backup was rebuilt.
+    pub fn repair(&mut self, game_root: &Path, replaced_files: &[GamePath]) -> Result<Vec<GamePath>, String> {
+        let objects_dir = self.dir.join(Self::OBJECTS_DIRNAME);
+        // Copies that were cut off never got renamed into place
+        if let Ok(objects) = fs::read_dir(&objects_dir) {
+            for object in objects.flatten() {
+                if object.path().extension().is_some_and(|e| e == "tmp") {
+                    let _ = fs::remove_file(object.path());
+                }
+            }
+        }
+
+        let mut rebuilt: Vec<GamePath> = vec![];
+        let mut lost: Vec<String> = vec![];
+        for (path, baseline) in self.originals.iter() {
+            let intact = self.object_size(&baseline.hash) == Some(baseline.size);
+            if intact {
+                continue;
+            }
+
+            let full_path = game_root.join(path.to_path());
+            let untouched = !replaced_files.contains(path) && !self.pending.contains(path)
+                && Self::read_baseline(&full_path).is_ok_and(|b| b == *baseline);
+            if !untouched || self.store_object(&full_path).is_err() {
+                lost.push(path.to_string());
+                continue;
+            }
+            rebuilt.push(path.clone());
+        }
+
+        if !lost.is_empty() {
+            return Err(format!("The backups of {} are missing or damaged, and the game's copies were changed. Verify the game files through Steam, then press \"Refresh Backup\"", lost.join(", ")));
+        }
+        Ok(rebuilt)
+    }
+}
@@ -0,0 +1,229 @@
+use super::MyWindow;
+use super::backup_store::BackupFormat;
+use super::dependency_graph::*;
+use super::mod_data::*;
+use super::mod_resolver::*;
+use super::mod_signing::*;
+
+use std::{fs, path::PathBuf};
+
+const USAGE: &str = "Usage:
+    nirvanamm graph [active|library] [dot|json] [output file]
+    nirvanamm why <guid> [active|library]
+    nirvanamm rdeps <guid> [active|library]
+    nirvanamm keygen <secret key file>
+    nirvanamm sign <mod zip> <secret key file>
+    nirvanamm trust <author> <public key> [guid prefix...]
+    nirvanamm status
+    nirvanamm snapshot [list|save|restore|delete] [name]
+    nirvanamm history
+    nirvanamm undo
+    nirvanamm backup-format [directory|zip|zstd]";
+
+/// Runs a command given on the command line instead of opening the window. The Ok value is what should be printed.
+pub fn run_command(args: &[String]) -> Result<String, String> {
+    match args[0].as_str() {
+        "graph" => graph_command(&args[1..]),
+        "why" => why_command(&args[1..]),
+        "rdeps" => rdeps_command(&args[1..]),
+        "keygen" => keygen_command(&args[1..]),
+        "sign" => sign_command(&args[1..]),
+        "trust" => trust_command(&args[1..]),
+        "status" => status_command(),
+        "snapshot" => snapshot_command(&args[1..]),
+        "history" => history_command(),
+        "undo" => undo_command(),
+        "backup-format" => backup_format_command(&args[1..]),
+        "help" | "--help" | "-h" => Ok(USAGE.to_string()),
+        c => Err(format!("Unknown command {}\n{}", c, USAGE))
+    }
+}
+
+fn get_set_graph(set_name: Option<&String>) -> Result<ModSetGraph, String> {
+    let library = MyWindow::load_library()?;
+    let mod_files: Vec<ModFile> = match set_name.map(|s| s.as_str()) {
+        None | Some("active") => {
+            let appcfg = MyWindow::get_appcfg();
+            match resolve_selection(&appcfg.data_win.active_mods, &library) {
+                Err(errors) => return Err(format!("Could not resolve the active mods:\n{}", errors.join("\n"))),
+                Ok(resolution) => resolution.required
+            }
+        },
+        Some("library") => library.iter().cloned().collect(),
+        Some(s) => return Err(format!("Unknown set {}, expected active or library", s))
+    };
+    let mod_metas: Vec<ModMetaData> = mod_files.into_iter().map(|m| m.metadata).collect();
+    Ok(ModSetGraph::new(&mod_metas))
+}
+
+fn graph_command(args: &[String]) -> Result<String, String> {
+    let graph = get_set_graph(args.first())?;
+    let output = match args.get(1).map(|s| s.as_str()) {
+        None | Some("dot") => graph.to_dot(),
+        Some("json") => graph.to_json()?,
+        Some(f) => return Err(format!("Unknown format {}, expected dot or json", f))
+    };
+
+    match args.get(2) {
+        None => Ok(output),
+        Some(out_path) => {
+            match fs::write(PathBuf::from(out_path), output) {
+                Err(e) => Err(format!("File write error: {}", e)),
+                Ok(_) => Ok(format!("Graph written to {}", out_path))
+            }
+        }
+    }
+}
+
+fn why_command(args: &[String]) -> Result<String, String> {
+    let guid = match args.first() {
+        None => return Err(USAGE.to_string()),
+        Some(g) => g
+    };
+    let graph = get_set_graph(args.get(1))?;
+    let chains = graph.why(guid)?;
+    if chains.is_empty() {
+        return Ok(format!("{} is not required by any other mod, so it was selected directly", guid));
+    }
+
+    let lines: Vec<String> = chains.iter().map(|c| c.join(" -> ")).collect();
+    Ok(format!("{} is required through:\n{}", guid, lines.join("\n")))
+}
+
+fn rdeps_command(args: &[String]) -> Result<String, String> {
+    let guid = match args.first() {
+        None => return Err(USAGE.to_string()),
+        Some(g) => g
+    };
+    let graph = get_set_graph(args.get(1))?;
+    let broken = graph.reverse_dependencies(guid)?;
+    if broken.is_empty() {
+        return Ok(format!("Nothing depends on {}, it can be disabled safely", guid));
+    }
+    Ok(format!("Disabling {} would break:\n{}", guid, broken.join("\n")))
+}
+
+fn keygen_command(args: &[String]) -> Result<String, String> {
+    let key_path = match args.first() {
+        None => return Err(USAGE.to_string()),
+        Some(p) => PathBuf::from(p)
+    };
+    if key_path.exists() {
+        return Err(format!("{} already exists, refusing to overwrite it", key_path.display()));
+    }
+
+    let (secret, public) = generate_key()?;
+    if let Err(e) = fs::write(&key_path, secret) {
+        return Err(format!("File write error: {}", e));
+    }
+    Ok(format!("Secret key written to {} (keep it private)\nPublic key: {}", key_path.display(), public))
+}
+
+fn sign_command(args: &[String]) -> Result<String, String> {
+    let (mod_path, key_path) = match (args.first(), args.get(1)) {
+        (Some(m), Some(k)) => (PathBuf::from(m), PathBuf::from(k)),
+        _ => return Err(USAGE.to_string())
+    };
+    let signing_key = load_signing_key(&key_path)?;
+    let public = sign_archive(&mod_path, &signing_key)?;
+    Ok(format!("Signed {}\nPublic key: {}", mod_path.display(), public))
+}
+
+fn trust_command(args: &[String]) -> Result<String, String> {
+    let (author, key) = match (args.first(), args.get(1)) {
+        (Some(a), Some(k)) => (a.clone(), k.clone()),
+        _ => return Err(USAGE.to_string())
+    };
+    let guid_prefixes: Vec<String> = args[2..].to_vec();
+
+    let mut trust_store = MyWindow::get_trust_store();
+    trust_store.keys.retain(|k| k.key != key);
+    trust_store.keys.push(TrustedKey { author: author.clone(), key, guid_prefixes });
+    trust_store.save()?;
+    Ok(format!("Trusted {}'s key", author))
+}
+
+fn status_command() -> Result<String, String> {
+    MyWindow::get_game_status().map(|s| s.to_string())
+}
+
+fn snapshot_command(args: &[String]) -> Result<String, String> {
+    let mut list = MyWindow::get_snapshot_list()?;
+    let action = args.first().map(|s| s.as_str()).unwrap_or("list");
+    if action == "list" {
+        if list.snapshots.is_empty() {
+            return Ok("No snapshots saved".to_string());
+        }
+        let lines: Vec<String> = list.snapshots.iter().map(|(name, snapshot)| format!("{}: {}", name, snapshot)).collect();
+        return Ok(lines.join("\n"));
+    }
+
+    let name = match args.get(1) {
+        None => return Err(USAGE.to_string()),
+        Some(n) => n
+    };
+    match action {
+        "save" => {
+            let snapshot = MyWindow::capture_snapshot(&MyWindow::get_appcfg())?;
+            let replaced = list.snapshots.insert(name.clone(), snapshot).is_some();
+            list.save()?;
+            Ok(format!("{} snapshot {}", if replaced { "Updated" } else { "Saved" }, name))
+        },
+        "restore" => {
+            let snapshot = list.get(name)?;
+            let mut appcfg = MyWindow::get_appcfg();
+            let previous = MyWindow::capture_previous(&appcfg);
+            let differences = MyWindow::restore_snapshot(&mut appcfg, snapshot)?;
+            MyWindow::record_apply(previous, &appcfg);
+            if differences.is_empty() {
+                return Ok(format!("Restored snapshot {}", name));
+            }
+            let paths: Vec<String> = differences.iter().map(|p| p.to_string()).collect();
+            Ok(format!("Restored snapshot {}, but these files differ from when it was saved (a mod may have been rebuilt without a new version):\n{}", name, paths.join("\n")))
+        },
+        "delete" => {
+            if list.snapshots.remove(name).is_none() {
+                return Err(format!("There is no snapshot named {}", name));
+            }
+            list.save()?;
+            Ok(format!("Deleted snapshot {}", name))
+        },
+        a => Err(format!("Unknown snapshot action {}, expected list, save, restore or delete", a))
+    }
+}
+
+fn history_command() -> Result<String, String> {
+    let history = MyWindow::get_apply_history()?;
+    if history.records.is_empty() {
+        return Ok("Nothing has been applied yet".to_string());
+    }
+    // Newest first, as that's the one undo goes back from
+    let lines: Vec<String> = history.records.iter().rev().enumerate().map(|(i, record)| {
+        let mut lines = vec![format!("{}. Before: {}", i + 1, record.previous)];
+        lines.extend(record.changes.iter().map(|c| format!("    {}", c)));
+        lines.join("\n")
+    }).collect();
+    Ok(lines.join("\n"))
+}
+
+fn undo_command() -> Result<String, String> {
+    let record = MyWindow::undo_last_apply(&mut MyWindow::get_appcfg())?;
+    Ok(format!("Back to {}", record.previous))
+}
+
+fn backup_format_command(args: &[String]) -> Result<String, String> {
+    let mut appcfg = MyWindow::get_appcfg();
+    if let Some(format_str) = args.first() {
+        appcfg.backup_format = match format_str.as_str() {
+            "directory" => BackupFormat::Directory,
+            "zip" => BackupFormat::Zip,
+            "zstd" => BackupFormat::Zstd,
+            f => return Err(format!("Unknown backup format {}, expected directory, zip or zstd", f))
+        };
+        appcfg.save()?;
+    }
+
+    let mut store = MyWindow::get_backup_store()?;
+    let converted = store.migrate()?;
+    Ok(format!("Backups are kept as {:?}, converted {} of them", appcfg.backup_format, converted))
+}
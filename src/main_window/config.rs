@@ -1,27 +1,80 @@
-use std::{fs, path::PathBuf};
+use std::{collections::BTreeMap, fs, path::PathBuf};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
-use crate::utils::files::get_appdata_dir;
+use super::archive_limits::ArchiveLimits;
+use super::backup_store::BackupFormat;
+use super::file_layers::FileOwnership;
+use super::ignore_list::IgnoreList;
+use super::install_policy::InstallPolicy;
+use crate::utils::{files::get_appdata_dir, paths::GamePath};
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ActiveModEnum {
+    GuidOnly(String),   // Configs from before versions were tracked
+    Versioned { guid: String, version: String }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(from = "ActiveModEnum")]
+pub struct ActiveMod {
+    pub guid: String,
+    pub version: String     // Empty if unknown, in which case the highest installed version is used
+}
+
+impl From<ActiveModEnum> for ActiveMod {
+    fn from(value: ActiveModEnum) -> Self {
+        match value {
+            ActiveModEnum::GuidOnly(guid) => ActiveMod { guid, version: String::new() },
+            ActiveModEnum::Versioned { guid, version } => ActiveMod { guid, version }
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct DataWinConfig {
     pub game_root: PathBuf,
-    pub active_mods: Vec<String>,
-    pub replaced_files: Vec<PathBuf>
+    pub active_mods: Vec<ActiveMod>,
+    #[serde(deserialize_with = "deserialize_replaced_files")]
+    pub replaced_files: Vec<GamePath>,
+    #[serde(default)]
+    pub file_owners: FileOwnership
+}
+
+/// Skips entries that aren't valid paths anymore, such as ones older versions accepted, instead of failing the whole config
+fn deserialize_replaced_files<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<GamePath>, D::Error> {
+    let entries = Vec::<String>::deserialize(deserializer)?;
+    Ok(entries.into_iter().filter_map(|entry| match GamePath::try_from(entry.clone()) {
+        Err(e) => {
+            log::warn!("Dropping replaced file {:?} from the config: {}", entry, e);
+            None
+        },
+        Ok(p) => Some(p)
+    }).collect())
 }
 
 // Custom default because data_path should always be set
 impl Default for DataWinConfig {
     fn default() -> Self {
         let game_root = PathBuf::from("C:\\Program Files (x86)\\Steam\\steamapps\\common\\ZeroRanger");
-        DataWinConfig { game_root, active_mods: vec![], replaced_files: vec![] }
+        DataWinConfig { game_root, active_mods: vec![], replaced_files: vec![], file_owners: FileOwnership::default() }
     }
 }
 
 #[derive(Serialize, Deserialize, Default, Clone)]
 pub struct AppConfig {
     pub data_win: DataWinConfig,
+    #[serde(default)]
+    pub install_policy: InstallPolicy,
+    #[serde(default)]
+    pub archive_limits: ArchiveLimits,
+    #[serde(default)]
+    pub ignore_list: IgnoreList,
+    #[serde(default)]
+    pub acknowledged_warnings: BTreeMap<String, Vec<String>>,  // Validation warnings the user chose to apply anyway, keyed by mod set
+    #[serde(default)]
+    pub backup_format: BackupFormat,
     #[serde(skip_serializing, skip_deserializing)]
     pub filepath: PathBuf
 }
@@ -0,0 +1,224 @@
+use super::mod_data::*;
+
+use serde::Serialize;
+
+use std::{collections::BTreeSet, fmt};
+
+#[derive(Clone, Debug)]
+pub enum DependencyError {
+    Missing(String, String, String),    // Requiring GUID, missing GUID, version requirement
+    Cycle(Vec<String>)                  // Path of GUIDs, where the first and last are the same
+}
+
+impl fmt::Display for DependencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DependencyError::Missing(requirer, guid, version) => write!(f, "{} requires {} {}, which is not available", requirer, guid, version),
+            DependencyError::Cycle(path) => write!(f, "Dependency cycle: {}", path.join(" -> "))
+        }
+    }
+}
+
+/// Every mod one mod depends on, itself included, found by walking its dependencies. Shared sub-dependencies are only visited once.
+#[derive(Clone)]
+pub struct DependencyGraph {
+    pub nodes: BTreeSet<String>     // GUIDs
+}
+
+impl DependencyGraph {
+    /// Will try and build a dependency graph. If a hard dependency is not satisfied or a cycle is found, it will return an Err describing it.
+    pub fn build(root: &ModMetaData, mod_metas: &Vec<ModMetaData>) -> Result<Self, DependencyError> {
+        let mut graph = Self { nodes: BTreeSet::new() };
+        let mut path: Vec<String> = vec![];
+        graph.visit(root, mod_metas, &mut path)?;
+        Ok(graph)
+    }
+
+    fn visit(&mut self, meta: &ModMetaData, mod_metas: &Vec<ModMetaData>, path: &mut Vec<String>) -> Result<(), DependencyError> {
+        // Already on the path we came from, so following it again would loop forever
+        if let Some(pos) = path.iter().position(|g| *g == meta.guid) {
+            let mut cycle = path[pos..].to_vec();
+            cycle.push(meta.guid.clone());
+            return Err(DependencyError::Cycle(cycle));
+        }
+        // Already fully visited through another branch
+        if self.nodes.contains(&meta.guid) {
+            return Ok(());
+        }
+
+        path.push(meta.guid.clone());
+        for d in meta.depends.iter() {
+            let dep = ModMetaData::get_dependency(d).unwrap();
+            match mod_metas.iter().find(|m| m.matches_dependency(&dep)) {
+                None => if !dep.soft {
+                    return Err(DependencyError::Missing(meta.guid.clone(), dep.guid, dep.version));
+                },
+                Some(dep_meta) => self.visit(dep_meta, mod_metas, path)?
+            }
+        }
+        path.pop();
+
+        self.nodes.insert(meta.guid.clone());
+        Ok(())
+    }
+
+    pub fn contains(&self, guid: &String) -> bool {
+        self.nodes.contains(guid)
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct SetNode {
+    pub id: String,
+    pub guid: String,
+    pub version: String,
+    pub name: String
+}
+
+#[derive(Serialize, Clone)]
+pub struct SetEdge {
+    pub from: String,
+    pub to: Option<String>,     // None if nothing in the set satisfies the requirement
+    pub guid: String,
+    pub version: String,        // Version requirement, not the version of `to`
+    pub soft: bool
+}
+
+/// A dependency graph over a whole set of mods, such as the library or the active mods.
+/// Unlike `DependencyGraph`, several versions of a GUID may be present, so nodes are identified by GUID and version.
+#[derive(Serialize, Clone)]
+pub struct ModSetGraph {
+    pub nodes: Vec<SetNode>,
+    pub edges: Vec<SetEdge>
+}
+
+impl ModSetGraph {
+    pub fn new(mod_metas: &[ModMetaData]) -> Self {
+        let nodes: Vec<SetNode> = mod_metas.iter().map(|m| SetNode {
+            id: Self::node_id(m),
+            guid: m.guid.clone(),
+            version: m.version.clone(),
+            name: m.name.clone()
+        }).collect();
+
+        let mut edges: Vec<SetEdge> = vec![];
+        for meta in mod_metas.iter() {
+            for dep in meta.depends.iter().map(|d| ModMetaData::get_dependency(d).unwrap()) {
+                // The highest version is the one the resolver would pick
+                let to = mod_metas.iter()
+                    .filter(|m| m.matches_dependency(&dep))
+                    .max_by_key(|m| semver::Version::parse(&m.version).unwrap())
+                    .map(Self::node_id);
+                edges.push(SetEdge { from: Self::node_id(meta), to, guid: dep.guid, version: dep.version, soft: dep.soft });
+            }
+        }
+
+        Self { nodes, edges }
+    }
+
+    fn node_id(meta: &ModMetaData) -> String {
+        format!("{}@{}", meta.guid, meta.version)
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph mods {\n");
+        for node in self.nodes.iter() {
+            dot.push_str(&format!("    \"{}\" [label=\"{}\\n{} {}\"];\n", dot_escape(&node.id), dot_escape(&node.name), dot_escape(&node.guid), dot_escape(&node.version)));
+        }
+        for edge in self.edges.iter() {
+            let style = if edge.soft { ", style=dashed" } else { "" };
+            match &edge.to {
+                Some(to) => dot.push_str(&format!("    \"{}\" -> \"{}\" [label=\"{}\"{}];\n", dot_escape(&edge.from), dot_escape(to), dot_escape(&edge.version), style)),
+                None => {
+                    // Missing dependencies get a node of their own so they still show up
+                    let missing_id = format!("{} {} (missing)", edge.guid, edge.version);
+                    dot.push_str(&format!("    \"{}\" [shape=box, color=red];\n", dot_escape(&missing_id)));
+                    dot.push_str(&format!("    \"{}\" -> \"{}\" [color=red{}];\n", dot_escape(&edge.from), dot_escape(&missing_id), style));
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn to_json(&self) -> Result<String, String> {
+        match serde_json::to_string_pretty(self) {
+            Err(e) => Err(format!("Serialize error: {}", e)),
+            Ok(j) => Ok(j)
+        }
+    }
+
+    fn find_ids(&self, guid: &str) -> Vec<String> {
+        self.nodes.iter().filter(|n| n.guid == guid).map(|n| n.id.clone()).collect()
+    }
+
+    fn hard_dependents(&self, id: &String) -> Vec<String> {
+        self.edges.iter().filter(|e| !e.soft && e.to.as_ref() == Some(id)).map(|e| e.from.clone()).collect()
+    }
+
+    /// Answers why a mod is in the set, as every chain of hard dependencies that leads to it. An empty chain list means nothing requires it.
+    pub fn why(&self, guid: &str) -> Result<Vec<Vec<String>>, String> {
+        let ids = self.find_ids(guid);
+        if ids.is_empty() {
+            return Err(format!("{} is not in the set", guid));
+        }
+
+        let mut chains: Vec<Vec<String>> = vec![];
+        for id in ids {
+            let mut path = vec![id.clone()];
+            self.collect_chains(&id, &mut path, &mut chains);
+        }
+        Ok(chains)
+    }
+
+    fn collect_chains(&self, id: &String, path: &mut Vec<String>, chains: &mut Vec<Vec<String>>) {
+        let dependents: Vec<String> = self.hard_dependents(id).into_iter().filter(|d| !path.contains(d)).collect();
+        if dependents.is_empty() {
+            // Reached something nothing else requires, as long as it isn't the mod we started from
+            if path.len() > 1 {
+                chains.push(path.iter().rev().cloned().collect());
+            }
+            return;
+        }
+        for dependent in dependents {
+            path.push(dependent.clone());
+            self.collect_chains(&dependent, path, chains);
+            path.pop();
+        }
+    }
+
+    /// Every mod that would lose a hard dependency, directly or through another mod, if the given GUID were disabled.
+    pub fn reverse_dependencies(&self, guid: &str) -> Result<Vec<String>, String> {
+        let mut queue = self.find_ids(guid);
+        if queue.is_empty() {
+            return Err(format!("{} is not in the set", guid));
+        }
+
+        let mut broken: Vec<String> = vec![];
+        while let Some(id) = queue.pop() {
+            for dependent in self.hard_dependents(&id) {
+                if !broken.contains(&dependent) && !dependent.starts_with(&format!("{}@", guid)) {
+                    broken.push(dependent.clone());
+                    queue.push(dependent);
+                }
+            }
+        }
+        broken.sort();
+        Ok(broken)
+    }
+}
+
+/// Makes text safe to put between quotes in a DOT file, since it comes from mod.toml files anyone can write
+fn dot_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => {},
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
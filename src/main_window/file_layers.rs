@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use super::backup_store::BackupStore;
+use crate::utils::paths::GamePath;
+
+/// A mod's version of a game file, kept in the backup store so it can be put back when a mod above it is removed
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct FileLayer {
+    pub guid: String,
+    pub hash: String,   // SHA-256 of the file right after this mod wrote it
+    #[serde(default)]
+    pub patched: bool   // Built from the layer below by a patch, rather than overwriting it
+}
+
+/// Which mods wrote each replaced file, bottom to top. The bottom of every stack is the game's own file (or none at all).
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(transparent)]
+pub struct FileOwnership {
+    pub layers: BTreeMap<GamePath, Vec<FileLayer>>
+}
+
+impl FileOwnership {
+    pub fn owned_by(&self, guid: &str) -> Vec<GamePath> {
+        self.layers.iter().filter(|(_, s)| s.iter().any(|l| l.guid == guid)).map(|(p, _)| p.clone()).collect()
+    }
+
+    /// Finds a file where a patch was built on top of the mod's version, which removing the mod alone would leave wrong
+    pub fn patched_over(&self, guid: &str) -> Option<GamePath> {
+        self.layers.iter().find(|(_, stack)| {
+            match stack.iter().position(|l| l.guid == guid) {
+                None => false,
+                Some(pos) => stack[pos + 1..].iter().any(|l| l.patched)
+            }
+        }).map(|(p, _)| p.clone())
+    }
+
+    /// Takes a mod's layer out of every file it wrote. Files it was on top of go back to the layer below, or to the game's own.
+    /// Nothing is recorded until every file is written, and files already written are put back if one fails. Returns the files no mod owns anymore.
+    pub fn remove(&mut self, store: &BackupStore, game_root: &Path, guid: &str) -> Result<Vec<GamePath>, String> {
+        // What each file's stack becomes, and the top it had if the file has to be rewritten
+        let mut planned: Vec<(GamePath, Vec<FileLayer>, Option<String>)> = vec![];
+        for path in self.owned_by(guid) {
+            let stack = &self.layers[&path];
+            let old_top = stack.last().filter(|l| l.guid == guid).map(|l| l.hash.clone());
+            let new_stack: Vec<FileLayer> = stack.iter().filter(|l| l.guid != guid).cloned().collect();
+            planned.push((path, new_stack, old_top));
+        }
+
+        // Lower layers are covered by whatever is still on top, so only the top needs writing
+        let mut written: Vec<(&GamePath, &String)> = vec![];
+        for (path, new_stack, old_top) in planned.iter() {
+            let old_top = match old_top {
+                None => continue,
+                Some(h) => h
+            };
+            let full_path = game_root.join(path.to_path());
+            let result = match new_stack.last() {
+                Some(below) => store.restore_object(&below.hash, &full_path),
+                None => match store.restore(game_root, path) {
+                    Ok(false) => Err(format!("No backup of {} was found", path)),
+                    r => r.map(|_| ())
+                }
+            };
+            if let Err(e) = result {
+                for (path, hash) in written.iter() {
+                    let full_path = game_root.join(path.to_path());
+                    if let Some(dir) = full_path.parent() {
+                        let _ = fs::create_dir_all(dir);
+                    }
+                    if let Err(undo_e) = store.restore_object(hash, &full_path) {
+                        log::error!("Could not put back {}: {}", path, undo_e);
+                    }
+                }
+                return Err(e);
+            }
+            written.push((path, old_top));
+        }
+        store.remove_created_dirs(game_root);
+
+        let mut released: Vec<GamePath> = vec![];
+        for (path, new_stack, _) in planned.into_iter() {
+            if new_stack.is_empty() {
+                self.layers.remove(&path);
+                released.push(path);
+            }
+            else {
+                self.layers.insert(path, new_stack);
+            }
+        }
+        Ok(released)
+    }
+
+    pub fn clear(&mut self) {
+        self.layers.clear();
+    }
+}
@@ -0,0 +1,125 @@
+use std::{fmt, fs, io::Read, path::Path};
+
+use walkdir::WalkDir;
+
+use super::backup_store::BackupStore;
+use super::file_layers::FileOwnership;
+use crate::utils::{hashing::sha256_hex, paths::GamePath};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Drift {
+    Modified,
+    Added,
+    Deleted
+}
+
+/// A file in the game directory that isn't what NirvanaMM left there
+pub struct DriftEntry {
+    pub path: GamePath,
+    pub drift: Drift,
+    pub owner: Option<String>   // The mod whose version was expected, if any
+}
+
+impl fmt::Display for DriftEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let drift = match self.drift {
+            Drift::Modified => "modified",
+            Drift::Added => "added",
+            Drift::Deleted => "deleted"
+        };
+        match &self.owner {
+            None => write!(f, "{:<9}{}", drift, self.path),
+            Some(guid) => write!(f, "{:<9}{} (from {})", drift, self.path, guid)
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct GameStatus {
+    pub drifted: Vec<DriftEntry>,
+    pub interrupted: bool   // An apply stopped partway, so the pending files may be in any state
+}
+
+impl fmt::Display for GameStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines: Vec<String> = vec![];
+        if self.interrupted {
+            lines.push("The last patch never finished, press \"Reset Origin\" to undo it".to_string());
+        }
+        if self.drifted.is_empty() {
+            lines.push("The game directory matches what NirvanaMM expects".to_string());
+        }
+        else {
+            lines.push(format!("{} file(s) changed outside of NirvanaMM:", self.drifted.len()));
+            lines.extend(self.drifted.iter().map(|d| d.to_string()));
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+/// Whether the file at the path has the given content, checking the size before hashing
+fn matches(full_path: &Path, hash: &str, size: u64) -> bool {
+    match fs::metadata(full_path) {
+        Ok(m) if m.len() == size => {
+            fs::File::open(full_path).is_ok_and(|mut f| sha256_hex(|buf| f.read(buf)).is_ok_and(|h| h == hash))
+        },
+        _ => false
+    }
+}
+
+/// Compares the game directory with what it should be: the files the active mods wrote, and the game's own everywhere else
+pub fn check_status(game_root: &Path, store: &BackupStore, owners: &FileOwnership) -> GameStatus {
+    let mut status = GameStatus { drifted: vec![], interrupted: !store.pending.is_empty() };
+    let mut push = |path: &GamePath, drift: Drift, owner: Option<String>| {
+        status.drifted.push(DriftEntry { path: path.clone(), drift, owner });
+    };
+
+    for (path, stack) in owners.layers.iter() {
+        let top = stack.last().unwrap();
+        let full_path = game_root.join(path.to_path());
+        let size = store.object_size(&top.hash).unwrap_or(0);
+        if !full_path.is_file() {
+            push(path, Drift::Deleted, Some(top.guid.clone()));
+        }
+        else if !matches(&full_path, &top.hash, size) {
+            push(path, Drift::Modified, Some(top.guid.clone()));
+        }
+    }
+
+    let unowned = |p: &GamePath| !owners.layers.contains_key(p) && !store.pending.contains(p);
+    for (path, baseline) in store.originals.iter().filter(|(p, _)| unowned(p)) {
+        let full_path = game_root.join(path.to_path());
+        if !full_path.is_file() {
+            push(path, Drift::Deleted, None);
+        }
+        else if !matches(&full_path, &baseline.hash, baseline.size) {
+            push(path, Drift::Modified, None);
+        }
+    }
+    // Files that were never backed up only have their size to go by
+    for (path, size) in store.manifest.iter().filter(|(p, _)| unowned(p) && !store.originals.contains_key(*p)) {
+        match fs::metadata(game_root.join(path.to_path())) {
+            Err(_) => push(path, Drift::Deleted, None),
+            Ok(m) if m.len() != *size => push(path, Drift::Modified, None),
+            Ok(_) => ()
+        }
+    }
+
+    // Without a listing of the game's own files, every file would look added
+    let listed = if store.manifest.is_empty() { vec![] } else { WalkDir::new(game_root).into_iter().flatten().collect() };
+    for entry in listed {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = match entry.path().strip_prefix(game_root).ok().and_then(|p| p.to_str()).map(GamePath::parse) {
+            Some(Ok(p)) => p,
+            _ => continue
+        };
+        if unowned(&path) && !store.manifest.contains_key(&path) && !store.originals.contains_key(&path) {
+            push(&path, Drift::Added, None);
+        }
+    }
+
+    status.drifted.sort_by(|a, b| a.path.cmp(&b.path).then(a.drift.cmp(&b.drift)));
+    status
+}
@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+/// Files that archivers add on their own, which no mod means to install
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct IgnoreList {
+    pub names: Vec<String>  // Matched against every file and directory name in an entry's path, regardless of case
+}
+
+impl Default for IgnoreList {
+    fn default() -> Self {
+        let names = ["__MACOSX", ".DS_Store", "Thumbs.db", "desktop.ini"];
+        IgnoreList { names: names.iter().map(|n| n.to_string()).collect() }
+    }
+}
+
+impl IgnoreList {
+    /// Whether a zip entry, or the directory it's in, is on the list
+    pub fn is_ignored(&self, entry: &str) -> bool {
+        entry.split(['/', '\\'])
+            .filter(|c| !c.is_empty())
+            .any(|c| self.names.iter().any(|n| n.eq_ignore_ascii_case(c)))
+    }
+}
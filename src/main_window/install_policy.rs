@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+
+use std::{collections::HashMap, fmt};
+
+use crate::utils::paths::GamePath;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExtensionMode {
+    Blocklist,  // Every extension except the listed ones may be installed
+    Allowlist   // Only the listed extensions may be installed
+}
+
+/// Loosens the policy for a single mod, keyed by its GUID
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PolicyOverride {
+    #[serde(default)]
+    pub allow_extensions: Vec<String>,
+    #[serde(default)]
+    pub allow_paths: Vec<String>        // Protected paths, or directories outside of the allowed ones
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]   // Missing fields take the shipped defaults
+pub struct InstallPolicy {
+    pub extension_mode: ExtensionMode,
+    pub extensions: Vec<String>,        // Without the leading dot
+    pub protected_paths: Vec<String>,   // Files or directories that no mod may write into
+    pub allowed_dirs: Vec<String>,      // If empty, files may go anywhere in the game directory
+    pub refuse_unsigned: bool,
+    pub refuse_unknown_keys: bool,      // Signed, but by a key that isn't in the trust store for that GUID
+    pub overrides: HashMap<String, PolicyOverride>
+}
+
+impl Default for InstallPolicy {
+    fn default() -> Self {
+        let extensions = [
+            "exe", "dll", "sys", "com", "scr", "pif", "cpl", "msi", "msp",
+            "bat", "cmd", "ps1", "psm1", "vbs", "vbe", "js", "jse", "wsf", "wsh", "hta",
+            "lnk", "url", "reg", "jar"
+        ];
+        InstallPolicy {
+            extension_mode: ExtensionMode::Blocklist,
+            extensions: extensions.iter().map(|e| e.to_string()).collect(),
+            protected_paths: vec!["data.win".to_string()],
+            allowed_dirs: vec![],
+            refuse_unsigned: false,
+            refuse_unknown_keys: false,
+            overrides: HashMap::new()
+        }
+    }
+}
+
+pub struct PolicyDenial {
+    pub entry: String,
+    pub rule: String
+}
+
+impl fmt::Display for PolicyDenial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is not allowed ({})", self.entry, self.rule)
+    }
+}
+
+impl InstallPolicy {
+    // Paths in the config that can't be parsed could never match a mod's file anyway
+    fn parse_all(paths: &[String]) -> Vec<GamePath> {
+        paths.iter().filter_map(|p| GamePath::parse(p).ok()).collect()
+    }
+
+    /// Checks an entry path of a mod against the policy. If it isn't allowed, the Err holds the rule that denied it.
+    pub fn check_entry(&self, guid: &str, entry_path: &GamePath, is_dir: bool) -> Result<(), PolicyDenial> {
+        let no_override = PolicyOverride::default();
+        let mod_override = self.overrides.get(guid).unwrap_or(&no_override);
+        let path_overridden = Self::parse_all(&mod_override.allow_paths).iter().any(|p| entry_path.is_within(p));
+        let deny = |rule: String| Err(PolicyDenial { entry: entry_path.to_string(), rule });
+
+        // A protected directory covers everything in it
+        if let Some(protected) = Self::parse_all(&self.protected_paths).iter().find(|p| entry_path.is_within(p)) {
+            if !path_overridden {
+                if protected == entry_path {
+                    return deny(format!("protected path {}", protected));
+                }
+                return deny(format!("inside the protected path {}", protected));
+            }
+        }
+
+        if !self.allowed_dirs.is_empty() && !path_overridden {
+            // Directories leading up to an allowed one have to be created too
+            let allowed = Self::parse_all(&self.allowed_dirs).iter().any(|d| entry_path.is_within(d) || (is_dir && d.is_within(entry_path)));
+            if !allowed {
+                return deny(format!("outside of the allowed directories {}", self.allowed_dirs.join(", ")));
+            }
+        }
+
+        if is_dir {
+            return Ok(());
+        }
+        // Case-insensitive, same as the file system the game runs on
+        let ext = match entry_path.to_path().extension() {
+            None => String::new(),
+            Some(e) => e.to_str().unwrap().to_lowercase()
+        };
+        let listed = self.extensions.iter().any(|e| e.trim_start_matches('.').to_lowercase() == ext);
+        let ext_overridden = mod_override.allow_extensions.iter().any(|e| e.trim_start_matches('.').to_lowercase() == ext);
+        match self.extension_mode {
+            ExtensionMode::Blocklist if listed && !ext_overridden => deny(format!("blocked extension .{}", ext)),
+            ExtensionMode::Allowlist if !listed && !ext_overridden => {
+                if ext.is_empty() {
+                    deny("files without an extension are not in the allowlist".to_string())
+                }
+                else {
+                    deny(format!("extension .{} is not in the allowlist", ext))
+                }
+            },
+            _ => Ok(())
+        }
+    }
+}
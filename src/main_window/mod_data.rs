@@ -1,11 +1,15 @@
 use semver::VersionReq;
 use serde::Deserialize;
-use zip::read::ZipFile;
+use zip::{read::ZipFile, ZipArchive};
 
-use std::{fs, io::{Read, Write}, path::PathBuf};
+use std::{collections::BTreeMap, fs, io::Read, path::PathBuf};
 
+use super::dependency_graph::*;
+use super::ignore_list::*;
+use super::mod_signing::SIGNATURE_ENTRY;
+use crate::utils::hashing::*;
+use crate::utils::paths::*;
 use crate::utils::stream::*;
-use crate::utils::xdelta3::*;
 
 #[derive(Deserialize, Default, Clone)]
 pub struct ModDependency {
@@ -34,7 +38,7 @@ pub struct ModMetaData {
 
 impl PartialEq for ModMetaData {
     fn eq(&self, other: &Self) -> bool {
-        self.guid == other.guid
+        self.guid == other.guid && self.version == other.version
     }
 }
 
@@ -98,46 +102,8 @@ impl ModMetaData {
         dep.guid == self.guid && req.matches(&ver)
     }
 
-    /// Will try and build a dependency tree. If a dependency is not satisfied, it will return an Err with the missing GUID.
-    pub fn get_dependency_tree(&self, mod_metas: &Vec<Self>) -> Result<DependencyNode, String> {
-        let guid = self.guid.clone();
-        let version = self.version.clone();
-        if self.depends.is_empty() {
-            return Ok(DependencyNode { guid, version, deps: None });
-        }
-        let mut deps = vec![];
-        for d in self.depends.iter() {
-            let dep = Self::get_dependency(d).unwrap();
-            match mod_metas.iter().find(|m| m.matches_dependency(&dep)) {
-                None => if !dep.soft { return Err(dep.guid.clone()) },
-                Some(mod_file) => {
-                    match mod_file.get_dependency_tree(mod_metas) {
-                        Err(g) => return Err(g),
-                        Ok(n) => deps.push(n),
-                    }
-                }
-            }
-        }
-        Ok(DependencyNode { guid, version, deps: Some(deps) })
-    }
-}
-
-#[derive(Clone)]
-pub struct DependencyNode {
-    pub guid: String,
-    pub version: String,
-    pub deps: Option<Vec<DependencyNode>>
-}
-
-impl DependencyNode {
-    pub fn in_dependency_tree(&self, guid: &String) -> bool {
-        if self.guid == *guid {
-            return true;
-        }
-        match &self.deps {
-            None => false,
-            Some(d) => d.iter().position(|d| d.in_dependency_tree(guid)).is_some()
-        }
+    pub fn get_dependency_graph(&self, mod_metas: &Vec<Self>) -> Result<DependencyGraph, DependencyError> {
+        DependencyGraph::build(self, mod_metas)
     }
 }
 
@@ -145,6 +111,8 @@ impl DependencyNode {
 pub struct ModFile {
     pub manifest: i32,
     pub metadata: ModMetaData,
+    #[serde(default)]
+    pub checksums: BTreeMap<String, String>,    // Entry path to SHA-256, can also be given with a sidecar file
     #[serde(skip_serializing, skip_deserializing)]
     pub filepath: PathBuf
 }
@@ -157,6 +125,8 @@ impl PartialEq for ModFile {
 
 impl ModFile {
     pub const SUBDIRECTORY: &str = "mods";
+    pub const CHECKSUMS_ENTRY: &str = "checksums.sha256";
+    pub const META_ENTRIES: [&str; 3] = ["mod.toml", SIGNATURE_ENTRY, Self::CHECKSUMS_ENTRY];   // Entries describing the mod, which are never installed
 
     pub fn new(filepath: PathBuf) -> Result<Self, String> {
         let filepath_str = filepath.to_str().unwrap();
@@ -182,78 +152,115 @@ impl ModFile {
         }
     }
 
-    pub fn extract_archive(&self, xd3: &XDelta3, game_root: &PathBuf, temp_dir: &PathBuf, replaced_files: &mut Vec<PathBuf>) -> Result<(), (String, String)> {
-        let guid = self.metadata.guid.clone();
-        let data_win = PathBuf::from("data.win");
-
-        let mut archive = match open_archive(&self.filepath) {
-            Err(e) => return Err((guid, e)),
-            Ok(z) => z
-        };
-        let entries: Vec<String> = archive.file_names().map(String::from).collect();    // Drops the immutable borrow by making a vector of new strings
-        for entry in entries.iter() {
-            if entry == "mod.toml" {
+    /// Lists what extracting would write in the game directory, marking directory entries. Patches write to data.win.
+    pub fn installed_paths(&self, ignore_list: &IgnoreList) -> Result<Vec<(GamePath, bool)>, String> {
+        let archive = open_archive(&self.filepath)?;
+        let mut paths: Vec<(GamePath, bool)> = vec![];
+        for entry in archive.file_names() {
+            if Self::META_ENTRIES.contains(&entry) || ignore_list.is_ignored(entry) {
                 continue;
             }
-
-            let is_patch = entry == "patch.xdelta";
-            let entry_path = PathBuf::from(entry);
-            if is_patch && !replaced_files.contains(&data_win) {
-                replaced_files.push(data_win.clone());
+            let entry = if entry == "patch.xdelta" { "data.win" } else { entry };
+            match GamePath::parse(entry) {
+                Err(e) => return Err(format!("Unsafe path {}: {}", entry, e)),
+                Ok(p) => paths.push((p, GamePath::is_dir_entry(entry)))
             }
-            else {
-                replaced_files.push(entry_path);
+        }
+        Ok(paths)
+    }
+
+    /// Reads the checksum sidecar, which follows the same format as sha256sum: a hash, two spaces, and the path on each line
+    fn read_checksum_sidecar(archive: &mut ZipArchive<fs::File>) -> Result<BTreeMap<String, String>, String> {
+        let mut checksums = BTreeMap::new();
+        let mut contents = String::new();
+        match archive.by_name(Self::CHECKSUMS_ENTRY) {
+            Err(_) => return Ok(checksums),
+            Ok(mut z) => if let Err(e) = z.read_to_string(&mut contents) {
+                return Err(format!("Failed to read {}: {}", Self::CHECKSUMS_ENTRY, e));
             }
+        }
 
-            let path =
-                if is_patch {
-                    temp_dir.join(entry)
-                } else {
-                    game_root.join(entry)
-                };
-            let dir = path.parent().unwrap();
-            if !dir.exists() {
-                let _ = fs::create_dir_all(dir);
+        for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+            match line.split_once(char::is_whitespace) {
+                None => return Err(format!("Malformed line in {}: {}", Self::CHECKSUMS_ENTRY, line)),
+                // sha256sum marks binary mode with an asterisk before the path
+                Some((hash, path)) => { checksums.insert(path.trim_start().trim_start_matches('*').to_string(), hash.to_lowercase()); }
             }
+        }
+        Ok(checksums)
+    }
 
-            match archive.by_name(entry.as_str()) {
-                Err(e) => return Err((guid, format!("Failed to read zip content: {}", e.to_string()))),
-                Ok(mut zip_file) => {
-                    if zip_file.is_dir() {
-                        let _ = fs::create_dir(path);
-                        continue;
-                    }
+    /// Checks every entry against the checksums given by the mod, before anything gets extracted.
+    /// If any don't match, it will return an Err listing each of them.
+    /// The Ok value lists the entries left out of the checksums, the Err value the ones that don't match them
+    pub fn verify_checksums(&self, ignore_list: &IgnoreList) -> Result<Vec<String>, Vec<String>> {
+        let mut archive = match open_archive(&self.filepath) {
+            Err(e) => return Err(vec![e]),
+            Ok(a) => a
+        };
 
-                    let mut out_file = match fs::File::create(&path) {
-                        Err(e) => return Err((guid, format!("Extract output error: {}", e.to_string()))),
-                        Ok(f) => f
-                    };
-                    // Better to stream with a buffer than to store the entire file in RAM
-                    if let Err(e) = stream_from_to::<32768>(|buf| zip_file.read(buf), |buf| out_file.write_all(buf)) {
-                        return Err((guid, format!("Failed to extract file {}: {}", entry, e)));
-                    }
-                }
+        let mut expected: BTreeMap<GamePath, String> = BTreeMap::new();
+        let sidecar = match Self::read_checksum_sidecar(&mut archive) {
+            Err(e) => return Err(vec![e]),
+            Ok(c) => c
+        };
+        for (path, hash) in self.checksums.iter().chain(sidecar.iter()) {
+            match GamePath::parse(path) {
+                Err(e) => return Err(vec![format!("{} (invalid path in checksums: {})", path, e)]),
+                Ok(p) => { expected.insert(p, hash.to_lowercase()); }
             }
+        }
+        if expected.is_empty() {
+            return Ok(vec![]);
+        }
 
-            if is_patch {
-                let data_out = game_root.join(&data_win);
-                let data_in = temp_dir.join (&data_win);
+        let mut corrupt: Vec<String> = vec![];
+        let mut unlisted: Vec<String> = vec![];
+        for i in 0..archive.len() {
+            let mut zip_file = match archive.by_index(i) {
+                Err(e) => return Err(vec![format!("Failed to read zip content: {}", e)]),
+                Ok(z) => z
+            };
+            let name = zip_file.name().to_string();
+            if zip_file.is_dir() || Self::META_ENTRIES.contains(&name.as_str()) {
+                continue;
+            }
+            // Paths were already checked in validation, so this would only be reached if they weren't
+            let entry_path = match GamePath::parse(&name) {
+                Err(e) => {
+                    corrupt.push(format!("{} ({})", name, e));
+                    continue;
+                },
+                Ok(p) => p
+            };
 
-                if let Err(_) = fs::rename(&data_out, &data_in) {
-                    // Rename only works if they are in the same file system, so we should catch cases that aren't like this
-                    match fs::copy(&data_out, &data_in) {
-                        Err(e) => return Err((guid, format!("Could not move old data.win to temp: {}", e.to_string()))),
-                        Ok(_) => {let _ = fs::remove_file(&data_out);}
+            // Once checksums are given, anything not listed could've been slipped in
+            let hash = match expected.remove(&entry_path) {
+                None => {
+                    // These never get installed, and archivers add them without the author knowing
+                    if !ignore_list.is_ignored(&name) {
+                        unlisted.push(name);
                     }
-                }
-
-                match xd3.decode(data_in, path, data_out) {
-                    Ok(_) => (),
-                    Err(e_msg) => return Err((guid, format!("Failed to patch due to an issue encountered by xdelta3.\n\n{}", e_msg)))
-                }
+                    continue;
+                },
+                Some(h) => h
+            };
+            match sha256_hex(|buf| zip_file.read(buf)) {
+                Err(e) => corrupt.push(format!("{} ({})", name, e)),
+                Ok(actual) if actual != hash => corrupt.push(format!("{} (expected {}, got {})", name, hash, actual)),
+                Ok(_) => ()
             }
         }
-        Ok(())
+        for missing in expected.keys() {
+            corrupt.push(format!("{} (missing from the archive)", missing));
+        }
+
+        if !corrupt.is_empty() {
+            Err(corrupt)
+        }
+        else {
+            Ok(unlisted)
+        }
     }
 
     fn parse_mod_metadata(mut mod_file: ZipFile) -> Result<Self, String> {
@@ -269,8 +276,8 @@ impl ModFile {
         }
     }
 
-    pub fn get_dependency_tree(&self, mod_files: &Vec<Self>) -> Result<DependencyNode, String> {
+    pub fn get_dependency_graph(&self, mod_files: &Vec<Self>) -> Result<DependencyGraph, DependencyError> {
         let mod_metas = mod_files.iter().map(|m| m.metadata.clone()).collect();
-        self.metadata.get_dependency_tree(&mod_metas)
+        self.metadata.get_dependency_graph(&mod_metas)
     }
 }
\ No newline at end of file
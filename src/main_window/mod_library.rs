@@ -0,0 +1,43 @@
+use super::mod_data::*;
+
+use std::collections::BTreeMap;
+
+/// Every installed mod, grouped by GUID. Versions of a GUID are kept from highest to lowest.
+#[derive(Clone, Default)]
+pub struct ModLibrary {
+    versions: BTreeMap<String, Vec<ModFile>>
+}
+
+impl ModLibrary {
+    pub fn new(mod_files: Vec<ModFile>) -> Self {
+        let mut versions: BTreeMap<String, Vec<ModFile>> = BTreeMap::new();
+        for mod_file in mod_files {
+            let group = versions.entry(mod_file.metadata.guid.clone()).or_default();
+            if group.contains(&mod_file) {
+                log::warn!("{} {} is installed more than once, ignoring {}", mod_file.metadata.guid, mod_file.metadata.version, mod_file.filepath.display());
+                continue;
+            }
+            group.push(mod_file);
+        }
+        // Versions are already checked when the mod file is loaded, so this shouldn't fail
+        for group in versions.values_mut() {
+            group.sort_by_key(|m| std::cmp::Reverse(semver::Version::parse(&m.metadata.version).unwrap()));
+        }
+        Self { versions }
+    }
+
+    pub fn get_versions(&self, guid: &str) -> &[ModFile] {
+        match self.versions.get(guid) {
+            None => &[],
+            Some(v) => v
+        }
+    }
+
+    pub fn get_highest(&self, guid: &str) -> Option<&ModFile> {
+        self.get_versions(guid).first()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ModFile> {
+        self.versions.values().flatten()
+    }
+}
@@ -0,0 +1,429 @@
+use super::config::ActiveMod;
+use super::mod_data::*;
+use super::mod_library::*;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+pub struct Resolution {
+    pub required: Vec<ModFile>,     // Chosen mods along with every hard dependency they pull in
+    pub added: Vec<ModFile>,        // Mods within `required` that were not chosen
+    pub optional: Vec<ModFile>      // Soft dependencies that are installed, but not required
+}
+
+/// Takes the chosen mods and returns the closure of mods they need from the library.
+/// Versions are picked highest first. Each conflict is worked back to its cause, which is remembered so the same dead end isn't tried again.
+/// If no set of installed versions works, it will return an Err with the chain of causes.
+pub fn resolve_selection(chosen: &[ActiveMod], library: &ModLibrary) -> Result<Resolution, Vec<String>> {
+    let mut chosen_versions: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for active in chosen.iter() {
+        let versions = chosen_versions.entry(active.guid.clone()).or_default();
+        if !versions.contains(&active.version) {
+            versions.push(active.version.clone());
+        }
+    }
+
+    let mut pins: BTreeMap<String, String> = BTreeMap::new();
+    let mut errors: Vec<String> = vec![];
+    for (guid, versions) in chosen_versions {
+        if versions.len() > 1 {
+            errors.push(format!("Several versions of {} are selected: {}", guid, versions.join(", ")));
+            continue;
+        }
+        // An unknown version comes from an older config, so any installed version will do
+        let version = if versions[0].is_empty() { "*".to_string() } else { format!("={}", versions[0]) };
+        pins.insert(guid, version);
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut solver = Solver::new(library, &pins);
+    let assignment = match solver.solve() {
+        Err(failure) => return Err(solver.explain(failure)),
+        Ok(a) => a
+    };
+
+    let mut required: Vec<ModFile> = vec![];
+    let mut added: Vec<ModFile> = vec![];
+    for (guid, mod_file) in assignment.iter() {
+        if !pins.contains_key(guid) {
+            added.push(mod_file.clone());
+        }
+        required.push(mod_file.clone());
+    }
+
+    let mut optional: Vec<ModFile> = vec![];
+    for mod_file in required.iter() {
+        for dep in mod_file.metadata.depends.iter().map(|d| ModMetaData::get_dependency(d).unwrap()) {
+            if !dep.soft || assignment.contains_key(&dep.guid) {
+                continue;
+            }
+            if let Some(soft_mod) = library.get_versions(&dep.guid).iter().find(|m| m.metadata.matches_dependency(&dep)) {
+                if !optional.contains(soft_mod) {
+                    optional.push(soft_mod.clone());
+                }
+            }
+        }
+    }
+
+    Ok(Resolution { required, added, optional })
+}
+
+/// States of a mod: 0 is it not being used at all, i + 1 is its i-th installed version (highest first)
+type States = BTreeSet<usize>;
+
+/// Says a mod is in one of the given states
+#[derive(Clone)]
+struct Term {
+    guid: String,
+    states: States
+}
+
+#[derive(Clone)]
+enum Cause {
+    Pin { guid: String, version: String },                  // The mod was selected
+    Dependency { requirer: String, guid: String, range: String },
+    Derived(usize, usize)                                   // Learned from these two incompatibilities
+}
+
+/// Terms that can't all hold at once. With no terms, nothing works at all.
+struct Incompatibility {
+    terms: Vec<Term>,
+    cause: Cause
+}
+
+/// Narrows down the states of a mod, either as a decision to try a version or because an incompatibility left nothing else
+struct Assignment {
+    guid: String,
+    states: States,
+    level: usize,
+    cause: Option<usize>    // The incompatibility it follows from, None for decisions
+}
+
+enum Relation {
+    Satisfied,
+    Contradicted,
+    Inconclusive
+}
+
+/// A PubGrub-style solver over the installed versions of each mod
+struct Solver<'a> {
+    library: &'a ModLibrary,
+    incompatibilities: Vec<Incompatibility>,
+    assignments: Vec<Assignment>,
+    level: usize,
+    dependencies_added: BTreeSet<(String, usize)>
+}
+
+impl<'a> Solver<'a> {
+    fn new(library: &'a ModLibrary, pins: &BTreeMap<String, String>) -> Self {
+        let mut solver = Self { library, incompatibilities: vec![], assignments: vec![], level: 0, dependencies_added: BTreeSet::new() };
+        // Not having a selected version is what's ruled out
+        for (guid, version) in pins.iter() {
+            let matching = solver.matching_states(guid, version);
+            let term = Term { guid: guid.clone(), states: solver.all_states(guid).difference(&matching).cloned().collect() };
+            solver.add_incompatibility(vec![term], Cause::Pin { guid: guid.clone(), version: version.clone() });
+        }
+        solver
+    }
+
+    fn all_states(&self, guid: &str) -> States {
+        (0..=self.library.get_versions(guid).len()).collect()
+    }
+
+    fn matching_states(&self, guid: &str, version: &str) -> States {
+        let dep = ModDependency { guid: guid.to_string(), soft: false, version: version.to_string() };
+        self.library.get_versions(guid).iter().enumerate()
+            .filter(|(_, m)| m.metadata.matches_dependency(&dep))
+            .map(|(i, _)| i + 1)
+            .collect()
+    }
+
+    /// Terms that allow every state always hold, so they're left out
+    fn add_incompatibility(&mut self, terms: Vec<Term>, cause: Cause) -> usize {
+        let terms = terms.into_iter().filter(|t| t.states != self.all_states(&t.guid)).collect();
+        self.incompatibilities.push(Incompatibility { terms, cause });
+        self.incompatibilities.len() - 1
+    }
+
+    /// The states a mod may still be in, going by the first `count` assignments
+    fn allowed(&self, guid: &str, count: usize) -> States {
+        let mut allowed = self.all_states(guid);
+        for assignment in self.assignments[..count].iter().filter(|a| a.guid == guid) {
+            allowed = allowed.intersection(&assignment.states).cloned().collect();
+        }
+        allowed
+    }
+
+    fn relation(&self, term: &Term, count: usize) -> Relation {
+        let allowed = self.allowed(&term.guid, count);
+        if allowed.is_subset(&term.states) {
+            Relation::Satisfied
+        }
+        else if allowed.is_disjoint(&term.states) {
+            Relation::Contradicted
+        }
+        else {
+            Relation::Inconclusive
+        }
+    }
+
+    fn solve(&mut self) -> Result<BTreeMap<String, ModFile>, usize> {
+        loop {
+            self.propagate()?;
+            if !self.decide() {
+                break;
+            }
+        }
+
+        let mut assignment: BTreeMap<String, ModFile> = BTreeMap::new();
+        for decision in self.assignments.iter().filter(|a| a.cause.is_none()) {
+            let state = *decision.states.first().unwrap();
+            assignment.insert(decision.guid.clone(), self.library.get_versions(&decision.guid)[state - 1].clone());
+        }
+        Ok(assignment)
+    }
+
+    /// Narrows down every mod an incompatibility leaves one way out for, until nothing changes.
+    /// The Err holds an incompatibility ruling out everything.
+    fn propagate(&mut self) -> Result<(), usize> {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for id in 0..self.incompatibilities.len() {
+                let count = self.assignments.len();
+                let mut unsettled: Vec<usize> = vec![];
+                let mut contradicted = false;
+                for (i, term) in self.incompatibilities[id].terms.iter().enumerate() {
+                    match self.relation(term, count) {
+                        Relation::Satisfied => (),
+                        Relation::Contradicted => contradicted = true,
+                        Relation::Inconclusive => unsettled.push(i)
+                    }
+                }
+                if contradicted || unsettled.len() > 1 {
+                    continue;
+                }
+                if unsettled.is_empty() {
+                    // The learned incompatibility leaves one way out after backtracking, which the next pass takes
+                    self.resolve_conflict(id)?;
+                }
+                else {
+                    let term = &self.incompatibilities[id].terms[unsettled[0]];
+                    let states = self.all_states(&term.guid).difference(&term.states).cloned().collect();
+                    self.assignments.push(Assignment { guid: term.guid.clone(), states, level: self.level, cause: Some(id) });
+                }
+                changed = true;
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// How many assignments it takes for a term to be satisfied
+    fn satisfied_after(&self, term: &Term) -> usize {
+        (0..=self.assignments.len()).find(|c| matches!(self.relation(term, *c), Relation::Satisfied)).unwrap()
+    }
+
+    /// Works back from an incompatibility the assignments break, learning what caused it, and undoes decisions until it no longer holds
+    fn resolve_conflict(&mut self, mut id: usize) -> Result<(), usize> {
+        loop {
+            let terms = self.incompatibilities[id].terms.clone();
+            if terms.is_empty() {
+                return Err(id);
+            }
+            // The assignment after which every term is satisfied
+            let satisfier = terms.iter().map(|t| self.satisfied_after(t)).max().unwrap() - 1;
+            let satisfier_guid = self.assignments[satisfier].guid.clone();
+            let previous_level = terms.iter()
+                .filter(|t| t.guid != satisfier_guid)
+                .map(|t| match self.satisfied_after(t) { 0 => 0, c => self.assignments[c - 1].level })
+                .max()
+                .unwrap_or(0);
+
+            let satisfier = &self.assignments[satisfier];
+            let cause = match satisfier.cause {
+                Some(c) if previous_level >= satisfier.level => c,
+                _ => {
+                    self.assignments.retain(|a| a.level <= previous_level);
+                    self.level = previous_level;
+                    return Ok(());
+                }
+            };
+
+            // Neither can hold, so their terms for the satisfier's mod can't either, alongside the rest of both
+            let mut merged: BTreeMap<String, States> = BTreeMap::new();
+            for term in terms.iter().chain(self.incompatibilities[cause].terms.iter()) {
+                let states = match merged.remove(&term.guid) {
+                    None => term.states.clone(),
+                    Some(s) if term.guid == satisfier_guid => s.union(&term.states).cloned().collect(),
+                    Some(s) => s.intersection(&term.states).cloned().collect()
+                };
+                merged.insert(term.guid.clone(), states);
+            }
+            let merged_terms = merged.into_iter().map(|(guid, states)| Term { guid, states }).collect();
+            id = self.add_incompatibility(merged_terms, Cause::Derived(id, cause));
+        }
+    }
+
+    /// Tries the highest allowed version of a mod that is required but not picked yet, preferring mods with the fewest versions left.
+    /// Returns false once every required mod is picked.
+    fn decide(&mut self) -> bool {
+        let count = self.assignments.len();
+        let decided: BTreeSet<&String> = self.assignments.iter().filter(|a| a.cause.is_none()).map(|a| &a.guid).collect();
+        let mentioned: BTreeSet<&String> = self.assignments.iter().map(|a| &a.guid).collect();
+        let next = mentioned.into_iter()
+            .filter(|g| !decided.contains(g))
+            .map(|g| (g.clone(), self.allowed(g, count)))
+            .filter(|(_, allowed)| !allowed.contains(&0))
+            .min_by_key(|(_, allowed)| allowed.len());
+        let (guid, allowed) = match next {
+            None => return false,
+            Some(n) => n
+        };
+        let state = *allowed.first().unwrap();
+
+        if self.dependencies_added.insert((guid.clone(), state)) {
+            self.add_dependencies(&guid, state);
+        }
+        self.level += 1;
+        self.assignments.push(Assignment { guid, states: BTreeSet::from([state]), level: self.level, cause: None });
+        true
+    }
+
+    /// Adds an incompatibility for each hard dependency of a version, covering every version of the mod that requires the same range
+    fn add_dependencies(&mut self, guid: &str, state: usize) {
+        let versions = self.library.get_versions(guid);
+        let hard_deps = |m: &ModFile| -> Vec<ModDependency> {
+            m.metadata.depends.iter().map(|d| ModMetaData::get_dependency(d).unwrap()).filter(|d| !d.soft).collect()
+        };
+        for dep in hard_deps(&versions[state - 1]) {
+            let requirers: States = versions.iter().enumerate()
+                .filter(|(_, m)| hard_deps(m).iter().any(|d| d.guid == dep.guid && d.version == dep.version))
+                .map(|(i, _)| i + 1)
+                .collect();
+            let matching = self.matching_states(&dep.guid, &dep.version);
+            let terms = vec![
+                Term { guid: guid.to_string(), states: requirers },
+                Term { guid: dep.guid.clone(), states: self.all_states(&dep.guid).difference(&matching).cloned().collect() }
+            ];
+            self.add_incompatibility(terms, Cause::Dependency { requirer: guid.to_string(), guid: dep.guid, range: dep.version });
+        }
+    }
+
+    fn describe_versions(&self, guid: &str, states: &States) -> String {
+        let versions = self.library.get_versions(guid);
+        if versions.len() > 1 && states.iter().filter(|s| **s != 0).count() == versions.len() {
+            return format!("any version of {}", guid);
+        }
+        let listed: Vec<String> = states.iter().filter(|s| **s != 0).map(|s| versions[s - 1].metadata.version.clone()).collect();
+        format!("{} {}", guid, listed.join(" or "))
+    }
+
+    /// Puts what an incompatibility rules out into words
+    fn describe(&self, id: usize) -> String {
+        let terms = &self.incompatibilities[id].terms;
+        // A term allowing the mod to be left out says it's needed in one of the other states
+        let (needed, used): (Vec<&Term>, Vec<&Term>) = terms.iter().partition(|t| t.states.contains(&0));
+        let needed_str = |t: &Term| self.describe_versions(&t.guid, &self.all_states(&t.guid).difference(&t.states).cloned().collect());
+        let used_str = used.iter().map(|t| self.describe_versions(&t.guid, &t.states)).collect::<Vec<String>>().join(" and ");
+        match (used.len(), needed.len()) {
+            (0, 0) => "no set of installed versions satisfies the selected mods".to_string(),
+            (0, 1) => format!("{} is required", needed_str(needed[0])),
+            (1, 0) => format!("{} cannot be used", used_str),
+            (_, 0) => format!("{} cannot be used together", used_str),
+            (_, 1) => format!("{} requires {}", used_str, needed_str(needed[0])),
+            _ => format!("{} cannot be used without {}", used_str, needed.iter().map(|t| needed_str(t)).collect::<Vec<String>>().join(" and "))
+        }
+    }
+
+    /// Puts an incompatibility that was given rather than learned into words, along with what's installed when nothing matches
+    fn describe_external(&self, id: usize) -> String {
+        let (guid, range, fact) = match &self.incompatibilities[id].cause {
+            Cause::Pin { guid, version } => (guid, version, format!("your selection requires {} {}", guid, version)),
+            Cause::Dependency { requirer, guid, range } => {
+                let requirer_states = self.incompatibilities[id].terms.iter().find(|t| t.guid == *requirer).map(|t| t.states.clone()).unwrap_or_default();
+                (guid, range, format!("{} requires {} {}", self.describe_versions(requirer, &requirer_states), guid, range))
+            },
+            Cause::Derived(..) => return self.describe(id)
+        };
+        let installed = self.library.get_versions(guid);
+        if installed.is_empty() {
+            format!("{} (not installed)", fact)
+        }
+        else if self.matching_states(guid, range).is_empty() {
+            format!("{} (installed: {})", fact, installed.iter().map(|m| m.metadata.version.clone()).collect::<Vec<String>>().join(", "))
+        }
+        else {
+            fact
+        }
+    }
+
+    /// Explains why an incompatibility holds, with a line for each learned one built from the two it was learned from.
+    /// Lines that later lines point back to are numbered.
+    fn explain(&self, id: usize) -> Vec<String> {
+        let mut lines: Vec<(usize, Vec<Reason>)> = vec![];
+        self.explain_into(id, &mut lines);
+
+        let referred: BTreeSet<usize> = lines.iter().flat_map(|(_, reasons)| reasons.iter()).filter_map(|r| match r {
+            Reason::Line(line_id) => Some(*line_id),
+            _ => None
+        }).collect();
+        let mut numbers: HashMap<usize, usize> = HashMap::new();
+        let mut text: Vec<String> = vec![];
+        for (line_id, reasons) in lines.iter() {
+            let reasons_str: Vec<String> = reasons.iter().map(|r| match r {
+                Reason::Fact(f) => f.clone(),
+                Reason::Previous => "that".to_string(),
+                Reason::Line(l) => format!("({})", numbers[l])
+            }).collect();
+            let line = match reasons_str.len() {
+                1 => format!("Because {}, this cannot work", reasons_str[0]),
+                _ => format!("Because {} and {}, {}", reasons_str[0], reasons_str[1], self.describe(*line_id))
+            };
+            if referred.contains(line_id) {
+                numbers.insert(*line_id, numbers.len() + 1);
+                text.push(format!("({}) {}", numbers[line_id], line));
+            }
+            else {
+                text.push(line);
+            }
+        }
+        text
+    }
+
+    fn explain_into(&self, id: usize, lines: &mut Vec<(usize, Vec<Reason>)>) {
+        let (first, second) = match self.incompatibilities[id].cause {
+            Cause::Derived(a, b) => (a, b),
+            _ => {
+                lines.push((id, vec![Reason::Fact(self.describe_external(id))]));
+                return;
+            }
+        };
+        let mut reasons: Vec<Reason> = vec![];
+        for cause in [first, second] {
+            if !matches!(self.incompatibilities[cause].cause, Cause::Derived(..)) {
+                reasons.push(Reason::Fact(self.describe_external(cause)));
+                continue;
+            }
+            if !lines.iter().any(|(line_id, _)| *line_id == cause) {
+                self.explain_into(cause, lines);
+            }
+            // Right after the line it comes from, there's no need to point back to it
+            if reasons.is_empty() && lines.last().is_some_and(|(line_id, _)| *line_id == cause) {
+                reasons.push(Reason::Previous);
+            }
+            else {
+                reasons.push(Reason::Line(cause));
+            }
+        }
+        lines.push((id, reasons));
+    }
+}
+
+/// Why a line of an explanation holds
+enum Reason {
+    Fact(String),   // Something given, like a dependency
+    Previous,       // The line right before
+    Line(usize)     // A learned incompatibility explained on an earlier line
+}
@@ -0,0 +1,283 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use zip::{write::SimpleFileOptions, ZipArchive, ZipWriter};
+
+use std::{collections::HashMap, fmt, fs, io::{Read, Write}, path::PathBuf, sync::{Mutex, OnceLock}, time::SystemTime};
+
+use crate::utils::{hashing::*, stream::*};
+
+pub const SIGNATURE_ENTRY: &str = "mod.sig";
+
+// Bumped if what gets signed ever changes, so old signatures don't verify against a different payload
+const PAYLOAD_HEADER: &str = "nirvanamm-signature-v1";
+
+#[derive(Serialize, Deserialize)]
+struct SignatureFile {
+    key: String,        // Base64 ed25519 public key
+    signature: String   // Base64 ed25519 signature over the payload
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TrustedKey {
+    pub author: String,
+    pub key: String,
+    #[serde(default)]
+    pub guid_prefixes: Vec<String>  // GUIDs this key may sign for, any GUID if empty
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct TrustStore {
+    #[serde(default)]
+    pub keys: Vec<TrustedKey>,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub filepath: PathBuf
+}
+
+impl TrustStore {
+    pub const FILENAME: &str = "trust.toml";
+
+    pub fn load(store_path: PathBuf) -> Self {
+        let mut store = if !store_path.exists() {
+            Self::default()
+        }
+        else {
+            match fs::read_to_string(&store_path) {
+                Err(e) => {
+                    log::error!("Trust store read error: {}", e);
+                    Self::default()
+                },
+                Ok(c) => {
+                    match toml::from_str::<Self>(&c) {
+                        Err(e) => {
+                            log::error!("Trust store parse error: {}", e);
+                            Self::default()
+                        },
+                        Ok(ts) => ts
+                    }
+                }
+            }
+        };
+        store.filepath = store_path;
+        store
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        match toml::to_string(self) {
+            Err(e) => Err(format!("Serialize error: {}", e)),
+            Ok(c) => {
+                match fs::write(&self.filepath, c) {
+                    Err(e) => Err(format!("File write error: {}", e)),
+                    Ok(_) => Ok(())
+                }
+            }
+        }
+    }
+
+    pub fn find(&self, key: &str, guid: &str) -> Option<&TrustedKey> {
+        self.keys.iter().find(|k| k.key == key && (k.guid_prefixes.is_empty() || k.guid_prefixes.iter().any(|p| guid.starts_with(p))))
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum SignatureStatus {
+    Trusted(String),    // Author the key belongs to
+    UnknownKey(String), // Key that isn't trusted for this GUID
+    Unsigned,
+    Tampered(String)    // Reason the signature didn't check out
+}
+
+impl fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureStatus::Trusted(author) => write!(f, "Signed by {}", author),
+            SignatureStatus::UnknownKey(key) => write!(f, "Signed by unknown key {}", key),
+            SignatureStatus::Unsigned => write!(f, "Unsigned"),
+            SignatureStatus::Tampered(reason) => write!(f, "Tampered ({})", reason)
+        }
+    }
+}
+
+/// Builds what gets signed: the hash of every file entry (including mod.toml) except the signature itself, sorted by name
+fn signing_payload(archive: &mut ZipArchive<fs::File>) -> Result<Vec<u8>, String> {
+    let mut lines: Vec<String> = vec![];
+    for i in 0..archive.len() {
+        let mut zip_file = match archive.by_index(i) {
+            Err(e) => return Err(format!("Failed to read zip content: {}", e)),
+            Ok(z) => z
+        };
+        if zip_file.is_dir() || zip_file.name() == SIGNATURE_ENTRY {
+            continue;
+        }
+        // A name with a line break could pass itself off as more than one line of the payload
+        if zip_file.name().chars().any(|c| c.is_control()) {
+            return Err(format!("entry name {:?} contains control characters", zip_file.name()));
+        }
+        let hash = sha256_hex(|buf| zip_file.read(buf))?;
+        lines.push(format!("{}  {}", hash, zip_file.name()));
+    }
+    lines.sort();
+
+    let mut payload = format!("{}\n", PAYLOAD_HEADER);
+    for line in lines {
+        payload.push_str(&line);
+        payload.push('\n');
+    }
+    Ok(payload.into_bytes())
+}
+
+fn decode_key(key: &str) -> Result<VerifyingKey, String> {
+    let bytes = match STANDARD.decode(key) {
+        Err(e) => return Err(format!("key is not base64: {}", e)),
+        Ok(b) => b
+    };
+    let bytes: [u8; 32] = match bytes.try_into() {
+        Err(_) => return Err("key is not 32 bytes long".to_string()),
+        Ok(b) => b
+    };
+    match VerifyingKey::from_bytes(&bytes) {
+        Err(e) => Err(format!("key is invalid: {}", e)),
+        Ok(k) => Ok(k)
+    }
+}
+
+pub fn verify_signature(filepath: &PathBuf, guid: &str, trust_store: &TrustStore) -> SignatureStatus {
+    match check_signed_contents(filepath) {
+        Err(status) => status,
+        Ok(key) => trusted_status(key, guid, trust_store)
+    }
+}
+
+/// A result of `check_signed_contents`, with the modification time and size the archive had
+type CachedCheck = (SystemTime, u64, Result<String, SignatureStatus>);
+static SIGNATURE_CACHE: OnceLock<Mutex<HashMap<PathBuf, CachedCheck>>> = OnceLock::new();
+
+/// Same as `verify_signature`, but only hashes an archive again once it was modified. Only for display, as the archive could be swapped while keeping its time and size.
+pub fn verify_signature_cached(filepath: &PathBuf, guid: &str, trust_store: &TrustStore) -> SignatureStatus {
+    let stamp = match fs::metadata(filepath).and_then(|m| m.modified().map(|t| (t, m.len()))) {
+        Err(_) => return verify_signature(filepath, guid, trust_store),
+        Ok(s) => s
+    };
+    let cache = SIGNATURE_CACHE.get_or_init(|| Mutex::new(HashMap::new()));
+    let cached = cache.lock().unwrap().get(filepath)
+        .filter(|(time, size, _)| (*time, *size) == stamp)
+        .map(|(_, _, result)| result.clone());
+    let result = match cached {
+        Some(r) => r,
+        None => {
+            let result = check_signed_contents(filepath);
+            cache.lock().unwrap().insert(filepath.clone(), (stamp.0, stamp.1, result.clone()));
+            result
+        }
+    };
+    match result {
+        Err(status) => status,
+        Ok(key) => trusted_status(key, guid, trust_store)
+    }
+}
+
+fn trusted_status(key: String, guid: &str, trust_store: &TrustStore) -> SignatureStatus {
+    match trust_store.find(&key, guid) {
+        None => SignatureStatus::UnknownKey(key),
+        Some(k) => SignatureStatus::Trusted(k.author.clone())
+    }
+}
+
+/// Checks that the contents match the archive's signature, whoever made it. The Ok value is the key it was signed with.
+fn check_signed_contents(filepath: &PathBuf) -> Result<String, SignatureStatus> {
+    let mut archive = match open_archive(filepath) {
+        Err(e) => return Err(SignatureStatus::Tampered(e)),
+        Ok(a) => a
+    };
+    let sig_file = match archive.by_name(SIGNATURE_ENTRY) {
+        Err(_) => return Err(SignatureStatus::Unsigned),
+        Ok(mut z) => {
+            let mut contents = String::new();
+            if let Err(e) = z.read_to_string(&mut contents) {
+                return Err(SignatureStatus::Tampered(format!("signature unreadable: {}", e)));
+            }
+            match toml::from_str::<SignatureFile>(&contents) {
+                Err(e) => return Err(SignatureStatus::Tampered(format!("signature unreadable: {}", e))),
+                Ok(sf) => sf
+            }
+        }
+    };
+
+    let key = match decode_key(&sig_file.key) {
+        Err(e) => return Err(SignatureStatus::Tampered(e)),
+        Ok(k) => k
+    };
+    let signature = match STANDARD.decode(&sig_file.signature).map(|b| Signature::from_slice(&b)) {
+        Ok(Ok(s)) => s,
+        _ => return Err(SignatureStatus::Tampered("signature is malformed".to_string()))
+    };
+    let payload = match signing_payload(&mut archive) {
+        Err(e) => return Err(SignatureStatus::Tampered(e)),
+        Ok(p) => p
+    };
+    if key.verify_strict(&payload, &signature).is_err() {
+        return Err(SignatureStatus::Tampered("contents do not match the signature".to_string()));
+    }
+    Ok(sig_file.key)
+}
+
+/// Adds a signature to a mod archive, returning the public key it can be verified with
+pub fn sign_archive(filepath: &PathBuf, signing_key: &SigningKey) -> Result<String, String> {
+    let mut archive = open_archive(filepath)?;
+    if archive.by_name(SIGNATURE_ENTRY).is_ok() {
+        return Err(format!("{} is already signed", filepath.display()));
+    }
+    let payload = signing_payload(&mut archive)?;
+    drop(archive);
+
+    let public_key = STANDARD.encode(signing_key.verifying_key().as_bytes());
+    let sig_file = SignatureFile { key: public_key.clone(), signature: STANDARD.encode(signing_key.sign(&payload).to_bytes()) };
+    let contents = match toml::to_string(&sig_file) {
+        Err(e) => return Err(format!("Serialize error: {}", e)),
+        Ok(c) => c
+    };
+
+    let file = match fs::OpenOptions::new().read(true).write(true).open(filepath) {
+        Err(e) => return Err(format!("Error opening archive {}: {}", filepath.display(), e)),
+        Ok(f) => f
+    };
+    let mut zip = match ZipWriter::new_append(file) {
+        Err(e) => return Err(format!("Error reading archive {}: {}", filepath.display(), e)),
+        Ok(z) => z
+    };
+    if let Err(e) = zip.start_file(SIGNATURE_ENTRY, SimpleFileOptions::default()) {
+        return Err(format!("Failed to add signature: {}", e));
+    }
+    if let Err(e) = zip.write_all(contents.as_bytes()) {
+        return Err(format!("Failed to add signature: {}", e));
+    }
+    if let Err(e) = zip.finish() {
+        return Err(format!("Failed to add signature: {}", e));
+    }
+    Ok(public_key)
+}
+
+/// Creates a new key pair, returning the secret and public keys in base64
+pub fn generate_key() -> Result<(String, String), String> {
+    let mut secret = [0u8; 32];
+    if let Err(e) = getrandom::getrandom(&mut secret) {
+        return Err(format!("Could not generate a key: {}", e));
+    }
+    let signing_key = SigningKey::from_bytes(&secret);
+    Ok((STANDARD.encode(secret), STANDARD.encode(signing_key.verifying_key().as_bytes())))
+}
+
+pub fn load_signing_key(key_path: &PathBuf) -> Result<SigningKey, String> {
+    let contents = match fs::read_to_string(key_path) {
+        Err(e) => return Err(format!("File read error: {}", e)),
+        Ok(c) => c
+    };
+    let bytes = match STANDARD.decode(contents.trim()) {
+        Err(e) => return Err(format!("Secret key is not base64: {}", e)),
+        Ok(b) => b
+    };
+    match <[u8; 32]>::try_from(bytes) {
+        Err(_) => Err("Secret key is not 32 bytes long".to_string()),
+        Ok(b) => Ok(SigningKey::from_bytes(&b))
+    }
+}
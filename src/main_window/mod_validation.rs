@@ -1,75 +1,264 @@
 use super::mod_data::*;
-use crate::utils::stream::*;
+use super::dependency_graph::*;
+use super::config::*;
+use super::ignore_list::*;
+use super::install_policy::*;
+use super::mod_signing::*;
+use crate::utils::{hashing::*, paths::*, stream::*, text::truncate_lines};
 
-use std::{collections::{HashMap, HashSet}, fs::File};
+use std::{collections::HashMap, fmt, fs::File, io::Read};
 
-pub enum ModCheckResult {
-    ModsOk(),
-    ModInsecurity(String, String),
-    FailedDependency(Vec<String>, Vec<String>),
-    FileConflict(String, Vec<String>, Vec<String>),
-    InvalidPatchNames(String, Vec<String>)
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Severity {
+    Warning,    // Can be acknowledged to apply anyway
+    Error       // Always blocks applying
+}
+
+pub enum DiagnosticKind {
+    ModInsecurity(String),          // Reason
+    FailedDependency(Vec<String>),  // Unsatisfied dependencies
+    DependencyCycle(Vec<String>),   // GUIDs along the cycle
+    PatchConflict,
+    FileConflict,                   // The mod applied last wins
+    InvalidPatchNames,
+    CorruptFiles,
+    UnknownFiles                    // Not listed in the mod's checksums
+}
+
+impl DiagnosticKind {
+    pub fn severity(&self) -> Severity {
+        match self {
+            FileConflict | InvalidPatchNames | UnknownFiles => Severity::Warning,
+            _ => Severity::Error
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            ModInsecurity(_) => "insecure",
+            FailedDependency(_) => "missing-dependency",
+            DependencyCycle(_) => "dependency-cycle",
+            PatchConflict => "patch-conflict",
+            FileConflict => "file-conflict",
+            InvalidPatchNames => "patch-name",
+            CorruptFiles => "corrupt",
+            UnknownFiles => "unknown-files"
+        }
+    }
+}
+
+/// One problem found with the active mods. The first mod is the one the problem was found in.
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub mods: Vec<String>,
+    pub files: Vec<String>
+}
+
+impl Diagnostic {
+    pub fn severity(&self) -> Severity {
+        self.kind.severity()
+    }
+
+    /// Identifies the problem across runs, so an acknowledgement only covers this exact problem
+    pub fn id(&self) -> String {
+        format!("{}:{}:{}", self.kind.name(), self.mods.join(","), self.files.join(","))
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let guid = self.mods.first().cloned().unwrap_or_default();
+        let files_str = self.files.join(", ");
+        let text = match &self.kind {
+            ModInsecurity(e_msg) => format!("Mod security failure in {}: {}", guid, e_msg),
+            FailedDependency(deps) => format!("{} is missing dependencies: {}", guid, deps.join(", ")),
+            DependencyCycle(cycle) => format!("Mods depend on each other in a cycle: {}", cycle.join(" -> ")),
+            PatchConflict => format!("Mod {} is incompatible with {}: incompatible patches", guid, self.mods[1..].join(", ")),
+            FileConflict => format!("Mod {} overwrites files of {}: {}", guid, self.mods[1..].join(", "), files_str),
+            InvalidPatchNames => format!("Mod {} has patches not named patch.xdelta: {}", guid, files_str),
+            CorruptFiles => format!("Mod {} has files that don't match its checksums, try downloading it again: {}", guid, files_str),
+            UnknownFiles => format!("Mod {} has files not listed in its checksums: {}", guid, files_str)
+        };
+        write!(f, "[{:?}] {}", self.severity(), text)
+    }
+}
+
+#[derive(Default)]
+pub struct ValidationReport {
+    pub diagnostics: Vec<Diagnostic>
+}
+
+impl ValidationReport {
+    // The popup can only fit so many lines, the rest go to the log
+    const SUMMARY_LINES: usize = 6;
+
+    fn push(&mut self, kind: DiagnosticKind, mods: Vec<String>, files: Vec<String>) {
+        self.diagnostics.push(Diagnostic { kind, mods, files });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+
+    pub fn count(&self, severity: Severity) -> usize {
+        self.diagnostics.iter().filter(|d| d.severity() == severity).count()
+    }
+
+    /// Drops the warnings that were already acknowledged, leaving what still needs the user's attention
+    pub fn without_acknowledged(self, acknowledged: &[String]) -> Self {
+        let diagnostics = self.diagnostics.into_iter()
+            .filter(|d| d.severity() == Severity::Error || !acknowledged.contains(&d.id()))
+            .collect();
+        Self { diagnostics }
+    }
+
+    pub fn warning_ids(&self) -> Vec<String> {
+        self.diagnostics.iter().filter(|d| d.severity() == Severity::Warning).map(|d| d.id()).collect()
+    }
+
+    pub fn summary(&self) -> String {
+        let lines: Vec<String> = self.diagnostics.iter().map(|d| d.to_string()).collect();
+        format!("{} error(s), {} warning(s)\n{}", self.count(Severity::Error), self.count(Severity::Warning), truncate_lines(lines, Self::SUMMARY_LINES))
+    }
+
+    pub fn log(&self) {
+        for diagnostic in self.diagnostics.iter() {
+            match diagnostic.severity() {
+                Severity::Error => log::error!("{}", diagnostic),
+                Severity::Warning => log::warn!("{}", diagnostic)
+            }
+        }
+    }
+}
+
+/// Names a set of mods regardless of the order they were selected in, for remembering acknowledgements
+pub fn mod_set_key(mod_files: &[ModFile]) -> String {
+    let mut ids: Vec<String> = mod_files.iter().map(|m| format!("{}@{}", m.metadata.guid, m.metadata.version)).collect();
+    ids.sort();
+    ids.join(",")
 }
 
 use zip::ZipArchive;
-use ModCheckResult::*;
+use DiagnosticKind::*;
 
-pub fn validate_active_mods(active_mod_files: &Vec<ModFile>) -> ModCheckResult {
-    let mut deps_unsatisfied: Vec<String> = vec![];
-    let mut mods_blame: Vec<String> = vec![];
+// Every mod that writes a path, along with the name of its entry, as mods may name the same path in different cases
+type FileOwners<'a> = HashMap<GamePath, Vec<(&'a ModFile, String)>>;
+type InstalledEntries = Vec<(GamePath, String)>;
 
-    let mut checked_files: HashMap<String, &ModFile> = HashMap::new();
+/// Checks every active mod, collecting all the problems found rather than stopping at the first one
+pub fn validate_active_mods(active_mod_files: &Vec<ModFile>, config: &AppConfig, trust_store: &TrustStore) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    let mut checked_files: FileOwners = HashMap::new();
+    let mut cycles_found: Vec<Vec<String>> = vec![];
 
     for mod_file in active_mod_files.iter() {
         let guid = mod_file.metadata.guid.clone();
-        let mod_zip = match open_archive(&mod_file.filepath) {
-            Err(_) => continue,
+        let mut mod_zip = match open_archive(&mod_file.filepath) {
+            Err(e_msg) => {
+                report.push(ModInsecurity(e_msg), vec![guid], vec![]);
+                continue;
+            },
             Ok(z) => z
         };
 
-        if let Err(e_msg) = check_mod_security(&mod_zip) {
-            return ModInsecurity(guid, e_msg);
+        // Nothing else can safely be read from an archive that breaks the limits
+        if let Err(e_msg) = config.archive_limits.check_archive(&mut mod_zip) {
+            report.push(ModInsecurity(e_msg), vec![guid], vec![]);
+            continue;
         }
 
-        if let Err(bad_patches) = check_patch_validity(&mod_zip) {
-            return InvalidPatchNames(guid.clone(), bad_patches);
+        for (entry, e_msg) in check_mod_security(&mod_zip, &guid, &config.install_policy, &config.ignore_list) {
+            report.push(ModInsecurity(e_msg), vec![guid.clone()], vec![entry]);
         }
 
-        if let Err(mut bad_deps) = check_mod_dependencies(active_mod_files, &mod_file.metadata.depends) {
-            deps_unsatisfied.append(&mut bad_deps);
-            mods_blame.push(guid);
-            continue;
+        if let Err(e_msg) = check_mod_signature(mod_file, trust_store, &config.install_policy) {
+            report.push(ModInsecurity(e_msg), vec![guid.clone()], vec![]);
+        }
+
+        match mod_file.verify_checksums(&config.ignore_list) {
+            Err(corrupt) => report.push(CorruptFiles, vec![guid.clone()], corrupt),
+            Ok(unlisted) if !unlisted.is_empty() => report.push(UnknownFiles, vec![guid.clone()], unlisted),
+            Ok(_) => ()
+        }
+
+        if let Err(bad_patches) = check_patch_validity(&mod_zip, &config.ignore_list) {
+            report.push(InvalidPatchNames, vec![guid.clone()], bad_patches);
         }
 
-        match check_mod_conflicts(&mut checked_files, active_mod_files, mod_file, &mod_zip) {
-            Err((conflict_mods, conflict_files)) => return FileConflict(guid, conflict_mods, conflict_files),
-            Ok(valid_files) => {
-                for file in valid_files {
-                    checked_files.insert(file, &mod_file);
+        if let Err(bad_deps) = check_mod_dependencies(active_mod_files, &mod_file.metadata.depends) {
+            report.push(FailedDependency(bad_deps), vec![guid.clone()], vec![]);
+        }
+        else if let Err(DependencyError::Cycle(cycle)) = mod_file.get_dependency_graph(active_mod_files) {
+            // Every mod along a cycle finds the same one, so only report it once
+            let mut members = cycle.clone();
+            members.sort();
+            members.dedup();
+            if !cycles_found.contains(&members) {
+                cycles_found.push(members.clone());
+                report.push(DependencyCycle(cycle), members, vec![]);
+            }
+        }
+
+        let (valid_files, conflicts) = check_mod_conflicts(&checked_files, active_mod_files, mod_file, &mut mod_zip, &config.ignore_list);
+        // Patches apply on top of each other, so unlike plain files, one can't simply win over the other
+        let (patch_conflicts, file_conflicts): (Vec<_>, Vec<_>) = conflicts.into_iter().partition(|(_, file)| file == "patch.xdelta");
+        for (conflicts, kind) in [(patch_conflicts, PatchConflict), (file_conflicts, FileConflict)] {
+            if conflicts.is_empty() {
+                continue;
+            }
+            let mut mods = vec![guid.clone()];
+            let mut files: Vec<String> = vec![];
+            for (owner, file) in conflicts {
+                if !mods.contains(&owner) {
+                    mods.push(owner);
+                }
+                if !files.contains(&file) {
+                    files.push(file);
                 }
             }
+            report.push(kind, mods, files);
+        }
+        // Files are recorded even when they conflict, so later mods are checked against them too
+        for (file, entry) in valid_files {
+            checked_files.entry(file).or_default().push((mod_file, entry));
         }
     }
-    
-    if deps_unsatisfied.len() > 0 {
-        FailedDependency(deps_unsatisfied, mods_blame)
-    }
-    else {
-        ModsOk()
-    }
+
+    report
 }
 
-fn check_mod_security(mod_zip: &ZipArchive<File>) -> Result<(), String> {
+/// Returns every entry that isn't safe to install, along with the reason why
+fn check_mod_security(mod_zip: &ZipArchive<File>, guid: &str, policy: &InstallPolicy, ignore_list: &IgnoreList) -> Vec<(String, String)> {
+    let mut denied: Vec<(String, String)> = vec![];
     for entry in mod_zip.file_names() {
-        if entry.ends_with(".exe") || entry.ends_with(".dll") {
-            return Err(format!("DISALLOWED FILE {}, REPORT IMMEDIATELY", entry));
+        let entry_path = match GamePath::parse(entry) {
+            Err(e) => {
+                denied.push((entry.to_string(), format!("UNSAFE PATH {} ({}), REPORT IMMEDIATELY", entry, e)));
+                continue;
+            },
+            Ok(p) => p
+        };
+
+        // None of these end up in the game directory
+        if ModFile::META_ENTRIES.contains(&entry) || entry == "patch.xdelta" || ignore_list.is_ignored(entry) {
+            continue;
         }
 
-        if entry == "data.win" {
-            return Err("data.win is not allowed to be overridden".to_string());
+        if let Err(denial) = policy.check_entry(guid, &entry_path, GamePath::is_dir_entry(entry)) {
+            denied.push((entry.to_string(), denial.to_string()));
         }
     }
-    Ok(())
+    denied
+}
+
+fn check_mod_signature(mod_file: &ModFile, trust_store: &TrustStore, policy: &InstallPolicy) -> Result<(), String> {
+    let status = verify_signature(&mod_file.filepath, &mod_file.metadata.guid, trust_store);
+    match status {
+        SignatureStatus::Tampered(_) => Err(format!("Signature check failed: {}", status)),
+        SignatureStatus::Unsigned if policy.refuse_unsigned => Err("Mod is unsigned, which is refused by the install policy".to_string()),
+        SignatureStatus::UnknownKey(_) if policy.refuse_unknown_keys => Err(format!("{}, which is refused by the install policy", status)),
+        _ => Ok(())
+    }
 }
 
 fn check_mod_dependencies(active_mod_files: &Vec<ModFile>, mod_depends: &Vec<ModDependencyEnum>) -> Result<(), Vec<String>> {
@@ -92,41 +281,81 @@ fn check_mod_dependencies(active_mod_files: &Vec<ModFile>, mod_depends: &Vec<Mod
     }
 }
 
-fn check_mod_conflicts(checked_files: &mut HashMap<String, &ModFile>, active_mod_files: &Vec<ModFile>, mod_file: &ModFile, mod_zip: &ZipArchive<File>) -> Result<Vec<String>, (Vec<String>, Vec<String>)> {
-    let mut conflicts: Vec<String> = mod_zip.file_names().map(String::from).filter(|e| checked_files.contains_key(e)).collect();
-    let mut resolved_conflicts: Vec<String> = vec![];
-    let mut resolved_mods: Vec<&&ModFile> = vec![];
-    for conflict_file in conflicts.iter() {
-        let mod_conflict = checked_files.get(conflict_file).unwrap();
-        // We can save computation time by checking if the mod was already found in the tree
-        if resolved_mods.contains(&mod_conflict) {
-            resolved_conflicts.push(conflict_file.clone());
-        }
+/// Lists the files a mod would write into the game directory along with their entry names, leaving out directory entries and the ones describing the mod
+fn get_installed_entries(mod_zip: &ZipArchive<File>, ignore_list: &IgnoreList) -> InstalledEntries {
+    mod_zip.file_names()
+        .filter(|e| !ModFile::META_ENTRIES.contains(e) && !GamePath::is_dir_entry(e) && !ignore_list.is_ignored(e))
+        .filter_map(|e| GamePath::parse(e).ok().map(|p| (p, e.to_string())))  // Invalid paths are reported by the security check
+        .collect()
+}
 
-        let mod_deps = mod_file.get_dependency_tree(active_mod_files).unwrap();
-        let conflict_deps = mod_conflict.get_dependency_tree(active_mod_files).unwrap();
-        if mod_deps.in_dependency_tree(&mod_conflict.metadata.guid) || conflict_deps.in_dependency_tree(&mod_file.metadata.guid) {
-            resolved_conflicts.push(conflict_file.clone());
-            resolved_mods.push(mod_conflict);
-        }
+fn get_entry_crc(mod_zip: &mut ZipArchive<File>, entry: &str) -> Option<(u32, u64)> {
+    let index = mod_zip.index_for_name(entry)?;
+    mod_zip.by_index_raw(index).ok().map(|z| (z.crc32(), z.size()))
+}
+
+fn get_entry_hash(mod_zip: &mut ZipArchive<File>, entry: &str) -> Option<String> {
+    let mut zip_file = mod_zip.by_name(entry).ok()?;
+    sha256_hex(|buf| zip_file.read(buf)).ok()
+}
+
+/// Whether two mods ship the exact same bytes for a file, in which case it doesn't matter which one is extracted last
+fn is_identical_entry(mod_zip: &mut ZipArchive<File>, entry: &str, other_mod: &ModFile, other_entry: &str) -> bool {
+    let mut other_zip = match open_archive(&other_mod.filepath) {
+        Err(_) => return false,
+        Ok(z) => z
+    };
+    // The central directory is enough to tell files apart, but not to tell they're the same, as CRC32 collides easily
+    match (get_entry_crc(mod_zip, entry), get_entry_crc(&mut other_zip, other_entry)) {
+        (Some(a), Some(b)) if a == b => (),
+        _ => return false
     }
-    for resolved in resolved_conflicts {
-        let pos = conflicts.iter().position(|s| *s == resolved).unwrap();
-        conflicts.remove(pos);
+    match (get_entry_hash(mod_zip, entry), get_entry_hash(&mut other_zip, other_entry)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false
     }
+}
 
-    if conflicts.len() > 0 {
-        // Hash sets always have unique data, so no duplicates here
-        let conflict_mods: HashSet<String> = conflicts.iter().map(|f| checked_files.get(f).unwrap().metadata.guid.clone()).collect();
-        Err((conflict_mods.into_iter().collect(), conflicts))
-    }
-    else {
-        Ok(mod_zip.file_names().map(String::from).filter(|e| e != "mod.toml").collect())
+/// Returns the files the mod installs, along with every mod and file it conflicts on
+fn check_mod_conflicts(checked_files: &FileOwners, active_mod_files: &Vec<ModFile>, mod_file: &ModFile, mod_zip: &mut ZipArchive<File>, ignore_list: &IgnoreList) -> (InstalledEntries, Vec<(String, String)>) {
+    let installed_entries = get_installed_entries(mod_zip, ignore_list);
+    let mut conflicts: Vec<(String, String)> = vec![];
+    // Unresolvable graphs are reported on their own, so here they only mean the mods aren't related
+    let mod_deps = mod_file.get_dependency_graph(active_mod_files).ok();
+    let mut related: HashMap<String, bool> = HashMap::new();
+    for (path, entry) in installed_entries.iter() {
+        let owners = match checked_files.get(path) {
+            None => continue,
+            Some(o) => o
+        };
+
+        for (owner, owner_entry) in owners.iter() {
+            // Each owner's graph is only built the first time it comes up
+            let is_related = *related.entry(owner.metadata.guid.clone()).or_insert_with(|| {
+                let mod_depends = mod_deps.as_ref().is_some_and(|g| g.contains(&owner.metadata.guid));
+                mod_depends || owner.get_dependency_graph(active_mod_files).is_ok_and(|g| g.contains(&mod_file.metadata.guid))
+            });
+            if is_related {
+                continue;
+            }
+
+            // Two identical patches would still both be applied, the second one to the output of the first
+            if entry != "patch.xdelta" && is_identical_entry(mod_zip, entry, owner, owner_entry) {
+                continue;
+            }
+
+            conflicts.push((owner.metadata.guid.clone(), path.to_string()));
+        }
     }
+
+    (installed_entries, conflicts)
 }
 
-fn check_patch_validity(mod_zip: &ZipArchive<File>) -> Result<(), Vec<String>> {
-    let bad_patches: Vec<String> = mod_zip.file_names().map(String::from).filter(|f| f.ends_with(".xdelta") && f != "patch.xdelta").collect();
+fn check_patch_validity(mod_zip: &ZipArchive<File>, ignore_list: &IgnoreList) -> Result<(), Vec<String>> {
+    let bad_patches: Vec<String> = mod_zip.file_names()
+        .filter(|f| f.ends_with(".xdelta") && *f != "patch.xdelta" && !ignore_list.is_ignored(f))
+        .map(String::from)
+        .collect();
     if bad_patches.len() > 0 {
         Err(bad_patches)
     }
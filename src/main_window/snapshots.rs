@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+
+use std::{collections::BTreeMap, fmt, fs, path::PathBuf};
+
+use super::config::ActiveMod;
+use super::file_layers::FileOwnership;
+use crate::utils::paths::GamePath;
+
+/// A set of applied mods, saved under a name so it can be switched back to later
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    pub active_mods: Vec<ActiveMod>,            // Every mod that was applied, dependencies included, at the exact version
+    pub files: BTreeMap<GamePath, String>       // SHA-256 of each file the mods left, to tell whether restoring gave the same game
+}
+
+impl Snapshot {
+    pub fn new(active_mods: Vec<ActiveMod>, owners: &FileOwnership) -> Self {
+        let files = owners.layers.iter().filter_map(|(p, s)| s.last().map(|l| (p.clone(), l.hash.clone()))).collect();
+        Self { active_mods, files }
+    }
+
+    /// Files that came out differently than when the snapshot was taken, such as when a mod was replaced with another build of the same version
+    pub fn differences(&self, owners: &FileOwnership) -> Vec<GamePath> {
+        let restored = Self::new(vec![], owners).files;
+        let mut paths: Vec<GamePath> = self.files.iter().filter(|(p, h)| restored.get(*p) != Some(h)).map(|(p, _)| p.clone()).collect();
+        paths.extend(restored.keys().filter(|p| !self.files.contains_key(*p)).cloned());
+        paths.sort();
+        paths
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.active_mods.is_empty() {
+            return write!(f, "no mods");
+        }
+        let mods: Vec<String> = self.active_mods.iter().map(|m| format!("{} {}", m.guid, m.version)).collect();
+        write!(f, "{}", mods.join(", "))
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct SnapshotList {
+    #[serde(default)]
+    pub snapshots: BTreeMap<String, Snapshot>,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub filepath: PathBuf
+}
+
+impl SnapshotList {
+    pub const FILENAME: &str = "snapshots.toml";
+
+    pub fn load(list_path: PathBuf) -> Result<Self, String> {
+        let mut list = if !list_path.exists() {
+            Self::default()
+        }
+        else {
+            match fs::read_to_string(&list_path) {
+                Err(e) => return Err(format!("File read error: {}", e)),
+                Ok(c) => match toml::from_str::<Self>(&c) {
+                    Err(e) => return Err(format!("Snapshot list parse error: {}", e)),
+                    Ok(sl) => sl
+                }
+            }
+        };
+        list.filepath = list_path;
+        Ok(list)
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        match toml::to_string(self) {
+            Err(e) => Err(format!("Serialize error: {}", e)),
+            Ok(c) => {
+                match fs::write(&self.filepath, c) {
+                    Err(e) => Err(format!("File write error: {}", e)),
+                    Ok(_) => Ok(())
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Snapshot, String> {
+        match self.snapshots.get(name) {
+            None => Err(format!("There is no snapshot named {}", name)),
+            Some(s) => Ok(s)
+        }
+    }
+}
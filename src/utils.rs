@@ -2,4 +2,8 @@
 
 pub mod stream;
 pub mod xdelta3;
-pub mod files;
\ No newline at end of file
+pub mod files;
+pub mod hashing;
+pub mod paths;
+pub mod workers;
+pub mod text;
\ No newline at end of file
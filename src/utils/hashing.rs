@@ -0,0 +1,29 @@
+use std::io;
+
+use sha2::{Digest, Sha256};
+
+use super::stream::{stream_from_to, stream_from_to_limited};
+
+/// Hashes everything the reader gives with SHA-256, returning it as lowercase hex
+pub fn sha256_hex(mut read: impl FnMut(&mut [u8]) -> io::Result<usize>) -> Result<String, String> {
+    let mut hasher = Sha256::new();
+    stream_from_to::<32768>(|buf| read(buf), |buf| {
+        hasher.update(buf);
+        Ok(())
+    })?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Copies everything the reader gives to the writer while hashing it, failing past `limit` bytes. Also returns how many bytes were copied.
+pub fn sha256_hex_copy(mut read: impl FnMut(&mut [u8]) -> io::Result<usize>, mut write: impl FnMut(&[u8]) -> io::Result<()>, limit: usize) -> Result<(String, usize), String> {
+    let mut hasher = Sha256::new();
+    let count = stream_from_to_limited::<32768>(|buf| read(buf), |buf| {
+        hasher.update(buf);
+        write(buf)
+    }, limit)?;
+    Ok((to_hex(&hasher.finalize()), count))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
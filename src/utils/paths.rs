@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+
+use std::{cmp::Ordering, fmt, hash::{Hash, Hasher}, path::PathBuf};
+
+// Names Windows opens as a device rather than a file, with any extension
+const DEVICE_NAMES: [&str; 24] = [
+    "CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"
+];
+
+/// Turns a zip entry name into a path relative to wherever it gets extracted, refusing anything that could escape it.
+/// Names Windows would write somewhere other than they say are refused too, so what gets checked is what ends up on disk.
+pub fn sanitize_entry_path(entry: &str) -> Result<PathBuf, String> {
+    let path = split_entry_path(entry)?;
+    for component in path.iter().map(|c| c.to_str().unwrap()) {
+        // "evil.exe." and "evil.exe " are both created as "evil.exe"
+        if component.ends_with('.') || component.ends_with(' ') {
+            return Err(format!("\"{}\" ends with a dot or space, which Windows drops", component));
+        }
+        let stem = component.split('.').next().unwrap().trim_end_matches(' ').to_uppercase();
+        if DEVICE_NAMES.contains(&stem.as_str()) {
+            return Err(format!("\"{}\" is a device name on Windows", component));
+        }
+    }
+    Ok(path)
+}
+
+fn split_entry_path(entry: &str) -> Result<PathBuf, String> {
+    let normalised = entry.replace('\\', "/");
+    if normalised.starts_with('/') {
+        return Err("absolute paths are not allowed".to_string());
+    }
+
+    let mut path = PathBuf::new();
+    for (i, component) in normalised.split('/').enumerate() {
+        match component {
+            "" | "." => continue,
+            ".." => return Err("parent directory references are not allowed".to_string()),
+            c if c.contains(':') => {
+                // Windows reads "C:" as a drive and "file:stream" as an alternate data stream
+                return if i == 0 && c.len() == 2 {
+                    Err("drive prefixes are not allowed".to_string())
+                }
+                else {
+                    Err("alternate data streams are not allowed".to_string())
+                };
+            },
+            c => path.push(c)
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        return Err("path is empty".to_string());
+    }
+    Ok(path)
+}
+
+/// A file's path relative to the game directory. Equality, ordering and hashing go by a case-folded key,
+/// since NTFS treats `Music/Song.ogg` and `music/song.ogg` as the same file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct GamePath {
+    path: String,   // Separated by forward slashes, keeping the case it was given in
+    key: String
+}
+
+impl GamePath {
+    pub fn parse(entry: &str) -> Result<Self, String> {
+        Ok(Self::from_path_buf(sanitize_entry_path(entry)?))
+    }
+
+    fn from_path_buf(path_buf: PathBuf) -> Self {
+        let components: Vec<&str> = path_buf.iter().map(|c| c.to_str().unwrap()).collect();
+        let path = components.join("/");
+        // NTFS compares names through its upcase table, which uppercasing char by char follows closely.
+        // Windows also drops trailing dots and spaces, so names saved before those were refused still meet the file they were written to.
+        let key = components.iter().map(|c| c.trim_end_matches(['.', ' ']).to_uppercase()).collect::<Vec<String>>().join("/");
+        Self { path, key }
+    }
+
+    /// Zip entries ending with a slash only describe a directory, so they don't stand for any file
+    pub fn is_dir_entry(entry: &str) -> bool {
+        entry.ends_with('/') || entry.ends_with('\\')
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn to_path(&self) -> PathBuf {
+        self.path.split('/').collect()
+    }
+
+    /// Whether this is the given directory or is inside of it
+    pub fn is_within(&self, dir: &GamePath) -> bool {
+        self.key == dir.key || self.key.starts_with(&format!("{}/", dir.key))
+    }
+}
+
+impl PartialEq for GamePath {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for GamePath {}
+
+impl Hash for GamePath {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+    }
+}
+
+impl PartialOrd for GamePath {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for GamePath {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+impl fmt::Display for GamePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path)
+    }
+}
+
+impl TryFrom<String> for GamePath {
+    type Error = String;
+
+    // Names saved before Windows' naming rules were checked are still read, so the files they stand for can be reset
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Ok(Self::from_path_buf(split_entry_path(&value)?))
+    }
+}
+
+impl From<GamePath> for String {
+    fn from(value: GamePath) -> Self {
+        value.path
+    }
+}
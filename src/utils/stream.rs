@@ -1,12 +1,24 @@
 use std::{fs, io, path::PathBuf};
 use zip::ZipArchive;
 
-pub fn stream_from_to<const N: usize>(mut read: impl FnMut(&mut [u8]) -> io::Result<usize>, mut write: impl FnMut(&[u8]) -> io::Result<()>) -> Result<usize, String> {
+pub fn stream_from_to<const N: usize>(read: impl FnMut(&mut [u8]) -> io::Result<usize>, write: impl FnMut(&[u8]) -> io::Result<()>) -> Result<usize, String> {
+    stream_from_to_limited::<N>(read, write, usize::MAX)
+}
+
+/// Same as `stream_from_to`, but fails once more than `limit` bytes have been read
+pub fn stream_from_to_limited<const N: usize>(mut read: impl FnMut(&mut [u8]) -> io::Result<usize>, mut write: impl FnMut(&[u8]) -> io::Result<()>, limit: usize) -> Result<usize, String> {
     let mut buf = [0u8; N];
-    let mut total = 0;
-    while let Ok(count) = read(&mut buf) {
-        if count == 0 {
-            break;
+    let mut total: usize = 0;
+    loop {
+        // Zip entries report a CRC mismatch or broken compressed data as a read error, which must not pass for the end of the file
+        let count = match read(&mut buf) {
+            Ok(0) => break,
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(format!("Failed to read: {}", e))
+        };
+        if total.saturating_add(count) > limit {
+            return Err(format!("Exceeded the limit of {} bytes", limit));
         }
         match write(&buf[..count]) {
             Ok(_) => total += count,
@@ -0,0 +1,9 @@
+/// Keeps the first `max_lines` lines for a popup, noting how many more there were and that the log has them all
+pub fn truncate_lines(lines: Vec<String>, max_lines: usize) -> String {
+    let hidden = lines.len().saturating_sub(max_lines);
+    let mut shown: Vec<String> = lines.into_iter().take(max_lines).collect();
+    if hidden > 0 {
+        shown.push(format!("...and {} more, see latest.log", hidden));
+    }
+    shown.join("\n")
+}
@@ -0,0 +1,41 @@
+use std::{sync::{atomic::{AtomicUsize, Ordering}, Mutex}, thread};
+
+/// Enough threads to keep the disk busy, more mostly just fight over it
+const MAX_WORKERS: usize = 8;
+
+/// Runs `work` on every item across a pool of threads, each with its own state from `init` (such as an open archive).
+/// The results come back in the same order as the items, however the work was split up.
+pub fn parallel_map_with<T: Sync, S, R: Send>(items: &[T], init: impl Fn() -> S + Sync, work: impl Fn(&mut S, &T) -> R + Sync) -> Vec<R> {
+    let worker_count = thread::available_parallelism().map(|n| n.get()).unwrap_or(1).min(MAX_WORKERS).min(items.len());
+    if worker_count <= 1 {
+        let mut state = init();
+        return items.iter().map(|item| work(&mut state, item)).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<(usize, R)>> = Mutex::new(Vec::with_capacity(items.len()));
+    thread::scope(|scope| {
+        for _ in 0..worker_count {
+            scope.spawn(|| {
+                let mut state = init();
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    if index >= items.len() {
+                        break;
+                    }
+                    let result = work(&mut state, &items[index]);
+                    results.lock().unwrap().push((index, result));
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Same as `parallel_map_with`, for work that needs no state of its own
+pub fn parallel_map<T: Sync, R: Send>(items: &[T], work: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    parallel_map_with(items, || (), |_, item| work(item))
+}